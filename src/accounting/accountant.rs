@@ -1,47 +1,98 @@
+use rust_decimal::Decimal;
+
+use crate::error::CryptoTaxError;
 use crate::prices::PriceInformation;
-use crate::accounting::{AccountingMethod, calculation};
+use crate::accounting::{AccountingMethod, Disposals, FeeTreatment, InventoryScope, LendingTreatment, LiquidityTreatment, Receipts, calculation};
+use crate::accounting::calculation::Settings;
+use crate::accounting::jurisdiction::TaxJurisdiction;
 use crate::accounting::reports::CapitalGainsReport;
 
 pub struct Accountant<'a> {
     price_information: &'a mut PriceInformation,
-    accounting_method: AccountingMethod,
-    base_asset: &'a str,
-    currency_precision: f64,
+    settings: Settings<'a>,
 }
 
 impl<'a> Accountant<'a> {
     pub fn new(price_information: &'a mut PriceInformation) -> Accountant<'a> {
         Accountant {
             price_information,
-            accounting_method: AccountingMethod::FIFO,
-            base_asset: "EUR",
-            currency_precision: 0.00000001f64,
+            settings: Settings {
+                accounting_method: AccountingMethod::FIFO,
+                base_asset: "EUR",
+                currency_precision: Decimal::new(1, 8),
+                check_base_balance: false,
+                inventory_scope: InventoryScope::Global,
+                jurisdiction: TaxJurisdiction::default(),
+                receipts: Receipts::default(),
+                disposals: Disposals::default(),
+                fees: FeeTreatment::Neutral,
+                liquidity: LiquidityTreatment::Taxable,
+                lending: LendingTreatment::NonDisposal,
+            },
         }
     }
 
-    pub fn method(&mut self, method: AccountingMethod) -> &'a mut Accountant {
-        self.accounting_method = method;
+    pub fn method(&mut self, method: AccountingMethod) -> &mut Accountant<'a> {
+        self.settings.accounting_method = method;
+        self
+    }
+
+    pub fn base_asset(&mut self, base_asset: &'a str) -> &mut Accountant<'a> {
+        self.settings.base_asset = base_asset;
+        self
+    }
+
+    pub fn precision(&mut self, precision: Decimal) -> &mut Accountant<'a> {
+        self.settings.currency_precision = precision;
+        self
+    }
+
+    pub fn check_base_balance(&mut self, check_base_balance: bool) -> &mut Accountant<'a> {
+        self.settings.check_base_balance = check_base_balance;
+        self
+    }
+
+    pub fn inventory_scope(&mut self, inventory_scope: InventoryScope) -> &mut Accountant<'a> {
+        self.settings.inventory_scope = inventory_scope;
+        self
+    }
+
+    pub fn jurisdiction(&mut self, jurisdiction: TaxJurisdiction) -> &mut Accountant<'a> {
+        self.settings.jurisdiction = jurisdiction;
+        self
+    }
+
+    pub fn receipts(&mut self, receipts: Receipts) -> &mut Accountant<'a> {
+        self.settings.receipts = receipts;
+        self
+    }
+
+    pub fn disposals(&mut self, disposals: Disposals) -> &mut Accountant<'a> {
+        self.settings.disposals = disposals;
+        self
+    }
+
+    pub fn fees(&mut self, fees: FeeTreatment) -> &mut Accountant<'a> {
+        self.settings.fees = fees;
         self
     }
 
-    pub fn base_asset(&mut self, base_asset: &'a str) -> &'a mut Accountant {
-        self.base_asset = base_asset;
+    pub fn liquidity(&mut self, liquidity: LiquidityTreatment) -> &mut Accountant<'a> {
+        self.settings.liquidity = liquidity;
         self
     }
 
-    pub fn precision(&mut self, precision: f64) -> &'a mut Accountant {
-        self.currency_precision = precision;
+    pub fn lending(&mut self, lending: LendingTreatment) -> &mut Accountant<'a> {
+        self.settings.lending = lending;
         self
     }
 
-    pub fn analyze_file(&mut self, path: &str) -> CapitalGainsReport {
+    pub fn analyze_file(&mut self, path: &str) -> Result<CapitalGainsReport, CryptoTaxError> {
         let report = calculation::calculate_capital_gains(
             path,
             self.price_information,
-            self.accounting_method,
-            self.base_asset,
-            self.currency_precision,
-        );
-        CapitalGainsReport::new(report)
+            &self.settings,
+        )?;
+        Ok(CapitalGainsReport::new(report))
     }
 }
\ No newline at end of file
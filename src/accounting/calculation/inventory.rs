@@ -1,38 +1,105 @@
-use std::collections::{VecDeque};
+use rust_decimal::Decimal;
 
 use crate::accounting::{AccountingMethod, InventoryChange};
+use crate::accounting::calculation::allocate;
 
+pub enum WithdrawalError {
+    /// The inventory holds less than requested; contains the missing amount
+    InsufficientFunds(Decimal),
+    /// A lot named for specific identification is not (or no longer) held
+    UnknownLot(u32),
+}
+
+/// Store of the lots (layers) of one asset, sorted by acquisition date
 pub struct Inventory {
-    layers: VecDeque<InventoryChange>,
+    layers: Vec<InventoryChange>,
     accounting_method: AccountingMethod,
-    currency_precision: f64
 }
 
 impl Inventory {
-    pub fn new(
-        accounting_method: AccountingMethod,
-        currency_precision: f64
-    ) -> Inventory {
+    pub fn new(accounting_method: AccountingMethod) -> Inventory {
         Inventory {
-            layers: VecDeque::new(),
+            layers: Vec::new(),
             accounting_method,
-            currency_precision,
         }
     }
 
     pub fn deposit(&mut self, inflow: InventoryChange) {
-        // add layer to inventory
-        match self.accounting_method {
-            AccountingMethod::FIFO => self.layers.push_back(inflow),
-            AccountingMethod::LIFO => self.layers.push_front(inflow),
+        // add layer to inventory, keeping the layers sorted by acquisition date
+        let position = self.layers
+            .iter()
+            .position(|layer| layer.datetime > inflow.datetime)
+            .unwrap_or(self.layers.len());
+
+        // merge with a neighbouring part of the same layer, e.g. when layers are moved between accounts
+        let neighbours = [position.checked_sub(1), Some(position)];
+        let merged = neighbours
+            .iter()
+            .flatten()
+            .filter_map(|neighbour| self.layers.get(*neighbour).map(|layer| (*neighbour, layer)))
+            .find(|(_, layer)| layer.tx_id == inflow.tx_id
+                && layer.datetime == inflow.datetime
+                && layer.base_price == inflow.base_price)
+            .map(|(neighbour, _)| neighbour);
+        match merged {
+            Some(neighbour) => self.layers[neighbour].amount += inflow.amount,
+            None => self.layers.insert(position, inflow),
         };
+
+        // with average cost, every deposit re-weights the price of the whole pool
+        if let AccountingMethod::AverageCost | AccountingMethod::UkPooling = self.accounting_method {
+            let balance = self.balance();
+            if !balance.is_zero() {
+                let total_costs: Decimal = self.layers
+                    .iter()
+                    .map(|layer| layer.amount * layer.base_price)
+                    .sum();
+                let average_price = (total_costs / balance).normalize();
+                for layer in self.layers.iter_mut() {
+                    layer.base_price = average_price;
+                }
+            }
+        }
+    }
+
+    pub fn balance(&self) -> Decimal {
+        self.layers.iter().map(|layer| layer.amount).sum()
     }
 
-    pub fn withdraw(&mut self, mut amount_to_withdraw: f64) -> Vec<InventoryChange> {
+    /// Withdraws the given amount, taking the layers in the order of the accounting method;
+    /// with specific identification, the given lots are consumed first
+    pub fn withdraw(
+        &mut self,
+        mut amount_to_withdraw: Decimal,
+        lots: &[u32],
+    ) -> Result<Vec<InventoryChange>, WithdrawalError> {
+        let balance = self.balance();
+        if amount_to_withdraw > balance {
+            return Err(WithdrawalError::InsufficientFunds(amount_to_withdraw - balance));
+        }
+
+        let mut lots = match self.accounting_method {
+            AccountingMethod::SpecificIdentification => lots.iter(),
+            _ => [].iter(),
+        };
         let mut res: Vec<InventoryChange> = Vec::new();
 
-        // withdraw assets layer by layer, using FIFO/LIFO accounting
-        while let Some(layer) = self.layers.front_mut() {
+        // withdraw assets layer by layer; a lot that is listed twice is only taken once
+        let mut taken_lots: Vec<u32> = Vec::new();
+        while amount_to_withdraw > Decimal::ZERO {
+            let index = match lots.next() {
+                Some(lot) if taken_lots.contains(lot) => continue,
+                Some(lot) => {
+                    taken_lots.push(*lot);
+                    self.layers
+                        .iter()
+                        .position(|layer| layer.tx_id == *lot)
+                        .ok_or(WithdrawalError::UnknownLot(*lot))?
+                },
+                None => self.next_layer(),
+            };
+            let layer = &mut self.layers[index];
+
             // determine costs
             let amount = amount_to_withdraw.min(layer.amount); // see how much we can take
 
@@ -46,21 +113,216 @@ impl Inventory {
 
             // subtract amount from layer, remove layer if empty
             layer.amount -= amount;
-            if layer.amount < self.currency_precision {
-                self.layers.pop_front();
+            if layer.amount.is_zero() {
+                self.layers.remove(index);
             }
 
-            // subtract from remaining amount, exit if none left
+            // subtract from remaining amount
             amount_to_withdraw -= amount;
-            if amount_to_withdraw < self.currency_precision {
-                break;
-            }
         }
 
-        if amount_to_withdraw > self.currency_precision {
-            panic!("insufficient funds");
-        };
+        Ok(res)
+    }
+
+    /// Splits a share of the costs off every layer, e.g. for the assets received from a hard fork;
+    /// returns the layers of the received amount, which keep the acquisition dates of their parent layers
+    pub fn split(&mut self, amount: Decimal, cost_share: Decimal) -> Vec<InventoryChange> {
+        let balance = self.balance();
+        if balance.is_zero() {
+            return Vec::new();
+        }
+
+        // distribute the received amount proportionally to the layers
+        let layer_amounts: Vec<Decimal> = self.layers.iter().map(|layer| layer.amount).collect();
+        let split_amounts = allocate(amount, &layer_amounts, |share| share);
+
+        let mut res: Vec<InventoryChange> = Vec::new();
+        for (layer, split_amount) in self.layers.iter_mut().zip(split_amounts) {
+            let split_costs = layer.amount * layer.base_price * cost_share;
+            if !split_amount.is_zero() {
+                res.push(InventoryChange {
+                    tx_id: layer.tx_id,
+                    datetime: layer.datetime,
+                    amount: split_amount,
+                    base_price: (split_costs / split_amount).normalize(),
+                });
+            }
+            layer.base_price = (layer.base_price * (Decimal::ONE - cost_share)).normalize();
+        }
 
         res
     }
-}
\ No newline at end of file
+
+    fn next_layer(&self) -> usize {
+        // index of the layer to take next, according to the accounting method;
+        // among layers with the same price, the oldest one is taken first
+        match self.accounting_method {
+            AccountingMethod::FIFO
+            | AccountingMethod::SpecificIdentification
+            | AccountingMethod::AverageCost
+            | AccountingMethod::UkPooling => 0,
+            AccountingMethod::LIFO => self.layers.len() - 1,
+            AccountingMethod::HIFO => self.layers
+                .iter()
+                .enumerate()
+                .rev()
+                .max_by_key(|(_, layer)| layer.base_price)
+                .map_or(0, |(index, _)| index),
+            AccountingMethod::LOFO => self.layers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, layer)| layer.base_price)
+                .map_or(0, |(index, _)| index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    fn day(day: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap() + Duration::days(day)
+    }
+
+    fn layer(tx_id: u32, amount: i64, base_price: i64) -> InventoryChange {
+        InventoryChange {
+            tx_id,
+            datetime: day(tx_id as i64),
+            amount: Decimal::from(amount),
+            base_price: Decimal::from(base_price),
+        }
+    }
+
+    fn inventory(accounting_method: AccountingMethod, layers: &[(u32, i64, i64)]) -> Inventory {
+        let mut inventory = Inventory::new(accounting_method);
+        for (tx_id, amount, base_price) in layers.iter() {
+            inventory.deposit(layer(*tx_id, *amount, *base_price));
+        }
+        inventory
+    }
+
+    /// (tx_id, amount, base price) of the withdrawn layers
+    fn withdraw(inventory: &mut Inventory, amount: i64, lots: &[u32]) -> Vec<(u32, Decimal, Decimal)> {
+        match inventory.withdraw(Decimal::from(amount), lots) {
+            Ok(outflows) => outflows
+                .into_iter()
+                .map(|outflow| (outflow.tx_id, outflow.amount, outflow.base_price))
+                .collect(),
+            Err(_) => panic!("withdrawal failed"),
+        }
+    }
+
+    fn taken(inventory: &mut Inventory, amount: i64, lots: &[u32]) -> Vec<u32> {
+        withdraw(inventory, amount, lots).into_iter().map(|(tx_id, _, _)| tx_id).collect()
+    }
+
+    const LAYERS: [(u32, i64, i64); 4] = [(1, 1, 200), (2, 1, 100), (3, 1, 200), (4, 1, 100)];
+
+    #[test]
+    fn takes_layers_in_the_order_of_the_accounting_method() {
+        assert_eq!(taken(&mut inventory(AccountingMethod::FIFO, &LAYERS), 4, &[]), vec![1, 2, 3, 4]);
+        assert_eq!(taken(&mut inventory(AccountingMethod::LIFO, &LAYERS), 4, &[]), vec![4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn takes_the_oldest_of_layers_with_the_same_price_first() {
+        assert_eq!(taken(&mut inventory(AccountingMethod::HIFO, &LAYERS), 4, &[]), vec![1, 3, 2, 4]);
+        assert_eq!(taken(&mut inventory(AccountingMethod::LOFO, &LAYERS), 4, &[]), vec![2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn takes_partial_layers_and_keeps_the_rest() {
+        let mut inventory = inventory(AccountingMethod::FIFO, &[(1, 3, 100), (2, 3, 200)]);
+        assert_eq!(withdraw(&mut inventory, 4, &[]), vec![
+            (1, Decimal::from(3), Decimal::from(100)),
+            (2, Decimal::from(1), Decimal::from(200)),
+        ]);
+        assert_eq!(inventory.balance(), Decimal::from(2));
+    }
+
+    #[test]
+    fn fails_with_the_missing_amount() {
+        let mut inventory = inventory(AccountingMethod::FIFO, &[(1, 3, 100)]);
+        assert!(matches!(
+            inventory.withdraw(Decimal::from(5), &[]),
+            Err(WithdrawalError::InsufficientFunds(missing)) if missing == Decimal::from(2)));
+    }
+
+    #[test]
+    fn takes_the_named_lots_first() {
+        let mut specific = inventory(AccountingMethod::SpecificIdentification, &LAYERS);
+        assert_eq!(taken(&mut specific, 3, &[3, 2]), vec![3, 2, 1]);
+        // lots are ignored by the other accounting methods
+        assert_eq!(taken(&mut inventory(AccountingMethod::FIFO, &LAYERS), 2, &[3]), vec![1, 2]);
+    }
+
+    #[test]
+    fn continues_with_the_next_lot_once_a_lot_is_exhausted() {
+        let mut inventory = inventory(
+            AccountingMethod::SpecificIdentification,
+            &[(1, 2, 100), (2, 2, 200), (3, 2, 300)]);
+        assert_eq!(withdraw(&mut inventory, 3, &[2, 3]), vec![
+            (2, Decimal::from(2), Decimal::from(200)),
+            (3, Decimal::from(1), Decimal::from(300)),
+        ]);
+
+        // lot 2 is no longer held
+        assert!(matches!(
+            inventory.withdraw(Decimal::ONE, &[2]),
+            Err(WithdrawalError::UnknownLot(2))));
+    }
+
+    #[test]
+    fn rejects_unknown_lots() {
+        let mut inventory = inventory(AccountingMethod::SpecificIdentification, &LAYERS);
+        assert!(matches!(
+            inventory.withdraw(Decimal::ONE, &[7]),
+            Err(WithdrawalError::UnknownLot(7))));
+    }
+
+    #[test]
+    fn takes_a_lot_that_is_listed_twice_only_once() {
+        let mut inventory = inventory(AccountingMethod::SpecificIdentification, &LAYERS);
+        assert_eq!(taken(&mut inventory, 2, &[3, 3]), vec![3, 1]);
+    }
+
+    #[test]
+    fn reweights_the_price_of_all_layers_with_average_cost() {
+        let mut inventory = inventory(AccountingMethod::AverageCost, &[(1, 1, 100), (2, 3, 200)]);
+        assert_eq!(withdraw(&mut inventory, 2, &[]), vec![
+            (1, Decimal::from(1), Decimal::from(175)),
+            (2, Decimal::from(1), Decimal::from(175)),
+        ]);
+
+        // 2 @ 175 plus 2 @ 125 = 4 @ 150
+        inventory.deposit(layer(3, 2, 125));
+        assert_eq!(withdraw(&mut inventory, 4, &[]), vec![
+            (2, Decimal::from(2), Decimal::from(150)),
+            (3, Decimal::from(2), Decimal::from(150)),
+        ]);
+    }
+
+    #[test]
+    fn splits_a_share_of_the_costs_off_every_layer() {
+        let mut inventory = inventory(AccountingMethod::FIFO, &[(1, 1, 100), (2, 3, 200)]);
+        let split = inventory.split(Decimal::from(8), Decimal::new(25, 2));
+
+        // the received amount is distributed by amount, and keeps the dates of the parent layers
+        let split: Vec<(u32, Decimal, Decimal)> = split
+            .into_iter()
+            .map(|layer| (layer.tx_id, layer.amount, layer.base_price))
+            .collect();
+        assert_eq!(split, vec![
+            (1, Decimal::from(2), Decimal::new(125, 1)),
+            (2, Decimal::from(6), Decimal::from(25)),
+        ]);
+
+        // the parent layers keep the remaining three quarters of their costs
+        assert_eq!(withdraw(&mut inventory, 4, &[]), vec![
+            (1, Decimal::from(1), Decimal::from(75)),
+            (2, Decimal::from(3), Decimal::from(150)),
+        ]);
+    }
+}
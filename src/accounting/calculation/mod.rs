@@ -1,110 +1,484 @@
 mod inventory;
+mod positions;
+mod uk_pooling;
 
 use std::collections::HashMap;
+use rust_decimal::{Decimal, RoundingStrategy};
 
+use crate::error::CryptoTaxError;
 use crate::prices::PriceInformation;
-use crate::accounting::{AccountingMethod, CashflowRecord, Purchase, InventoryChange, Sale, TransactionRecord, Withdrawal};
-use crate::accounting::calculation::inventory::Inventory;
+use crate::accounting::{AccountingMethod, CashflowRecord, CostBasis, Disposal, DisposalKind, DisposalTreatment, Disposals, FeeTreatment, Income, IncomeKind, InventoryScope, LendingTreatment, LiquidityTreatment, ReceiptTreatment, Receipts, Purchase, InventoryChange, Sale, TransactionKind, TransactionRecord, Withdrawal, is_token};
+use crate::accounting::calculation::inventory::{Inventory, WithdrawalError};
+use crate::accounting::calculation::positions::PositionLedger;
+use crate::accounting::calculation::uk_pooling::UkPooling;
+use crate::accounting::jurisdiction::TaxJurisdiction;
+
+/// Settings of a capital gains calculation
+pub struct Settings<'a> {
+    pub accounting_method: AccountingMethod,
+    pub base_asset: &'a str,
+    pub currency_precision: Decimal,
+    pub check_base_balance: bool,
+    pub inventory_scope: InventoryScope,
+    pub jurisdiction: TaxJurisdiction,
+    pub receipts: Receipts,
+    pub disposals: Disposals,
+    pub fees: FeeTreatment,
+    pub liquidity: LiquidityTreatment,
+    pub lending: LendingTreatment,
+}
 
 pub fn calculate_capital_gains<'a>(
     records_path: &'a str,
     price_information: &'a mut PriceInformation,
-    accounting_method: AccountingMethod,
-    base_asset: &'a str,
-    currency_precision: f64,
-) -> Vec<CashflowRecord> {
-    let mut calculation = CapitalGainsCalculation::new(
-        price_information,
-        accounting_method,
-        base_asset,
-        currency_precision,
-    );
+    settings: &'a Settings<'a>,
+) -> Result<Vec<CashflowRecord>, CryptoTaxError> {
+    let mut calculation = CapitalGainsCalculation::new(price_information, settings);
 
     let mut reader = csv::ReaderBuilder::new()
-        .has_headers(false)
         .from_path(records_path)
-        .unwrap();
+        .map_err(CryptoTaxError::Input)?;
+    let headers = reader
+        .headers()
+        .map_err(CryptoTaxError::Input)?
+        .clone();
 
     let records = reader
-        .deserialize::<TransactionRecord>()
-        .skip(1)
+        .records()
         .enumerate();
 
+    // the rows of a multi-leg transaction, e.g. of a liquidity deposit, share their transaction id;
+    // they are collected, and processed together once the transaction is complete
+    let mut legs: Vec<TransactionRecord> = Vec::new();
+    let mut legs_line = 0;
+
     for (i, record) in records
     {
+        let record = record.map_err(CryptoTaxError::Input)?;
+        let line = record.position().map_or(0, |position| position.line());
+        let row = record
+            .deserialize::<TransactionRecord>(Some(&headers))
+            .map(TransactionRecord::with_token_ids)
+            .map_err(|source| CryptoTaxError::Parse { line, source })?;
+        validate(&row).map_err(|source| CryptoTaxError::Transaction {
+            tx_id: row.tx_id,
+            line,
+            source: Box::new(source),
+        })?;
+
+        if legs.first().is_some_and(|leg| leg.tx_id != row.tx_id || leg.kind != row.kind) {
+            calculation.process_legs(std::mem::take(&mut legs), legs_line)?;
+        }
+        // the pending legs are processed first, so that their console output precedes the progress line
         println!("Processing record #{}", i + 1);
-        calculation.process_record(record.unwrap());
+
+        if row.kind.is_multi_leg() {
+            if legs.is_empty() {
+                legs_line = line;
+            }
+            legs.push(row);
+            continue;
+        }
+
+        let tx_id = row.tx_id;
+        calculation
+            .process_record(row)
+            .map_err(|source| CryptoTaxError::Transaction {
+                tx_id,
+                line,
+                source: Box::new(source),
+            })?;
+    }
+    if !legs.is_empty() {
+        calculation.process_legs(legs, legs_line)?;
+    }
+
+    // with UK share pooling, the disposals can only be matched once all acquisitions are known
+    let mut log = calculation.log;
+    if let Some(uk_pooling) = calculation.uk_pooling {
+        log.extend(uk_pooling.finish(settings.currency_precision));
+        log.sort_by_key(|entry| entry.datetime_out.or(entry.datetime_in));
+    }
+
+    Ok(log)
+}
+
+/// Rounds a currency value to the nearest multiple of the configured precision
+fn round(value: Decimal, currency_precision: Decimal) -> Decimal {
+    let multiple = (value / currency_precision)
+        .round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero);
+    (multiple * currency_precision).normalize()
+}
+
+/// Splits a total in proportion to the given weights; every share but the last is rounded with the given
+/// function, and the last share receives the remainder, so that no rounding differences are lost
+fn allocate(total: Decimal, weights: &[Decimal], round: impl Fn(Decimal) -> Decimal) -> Vec<Decimal> {
+    let total_weight: Decimal = weights.iter().sum();
+    let mut remaining = total;
+    let count = weights.len();
+    weights
+        .iter()
+        .enumerate()
+        .map(|(i, weight)| {
+            let share = match i + 1 < count && !total_weight.is_zero() {
+                true => round(total * weight / total_weight),
+                false => remaining,
+            };
+            remaining -= share;
+            share
+        })
+        .collect()
+}
+
+/// Fees of the rows of a multi-leg transaction: the fees in the base asset, and the fees in foreign assets
+struct Fees {
+    base: Decimal,
+    foreign: Vec<(String, Decimal)>,
+}
+
+impl Fees {
+    fn collect(rows: &[TransactionRecord], base_asset: &str) -> Fees {
+        let mut fees = Fees {
+            base: Decimal::ZERO,
+            foreign: Vec::new(),
+        };
+        for row in rows.iter() {
+            if let (Some(fee_asset), Some(fee_amount)) = (&row.fee_asset, row.fee_amount) {
+                match fee_asset == base_asset {
+                    true => fees.base += fee_amount,
+                    false => fees.foreign.push((fee_asset.clone(), fee_amount)),
+                };
+            }
+        }
+        fees
+    }
+
+    /// Takes the fees paid in the given asset, so that the caller can add them to the moved amount
+    fn take(&mut self, asset: &str) -> Decimal {
+        let amount = self.foreign
+            .iter()
+            .filter(|(fee_asset, _)| fee_asset == asset)
+            .map(|(_, amount)| *amount)
+            .sum();
+        self.foreign.retain(|(fee_asset, _)| fee_asset != asset);
+        amount
+    }
+}
+
+/// Treatment of staking rewards and lending interest
+const INCOME_AT_MARKET: ReceiptTreatment = ReceiptTreatment {
+    cost_basis: CostBasis::Market,
+    income: true,
+};
+
+/// Rejects amounts that are zero or negative; fees and acquisition costs may be zero
+fn validate(row: &TransactionRecord) -> Result<(), CryptoTaxError> {
+    let amounts = [
+        (row.out_amount, "out_amount", false),
+        (row.in_amount, "in_amount", false),
+        (row.fee_amount, "fee_amount", true),
+        (row.acquisition_costs, "acquisition_costs", true),
+    ];
+    for (amount, field, zero_allowed) in amounts.iter() {
+        if let Some(amount) = amount {
+            if amount.is_sign_negative() || (amount.is_zero() && !zero_allowed) {
+                return Err(CryptoTaxError::InvalidAmount(field));
+            }
+        }
     }
+    Ok(())
+}
+
+/// Price per unit of the given costs; fails if the amount is not positive, e.g. if a fee used it up
+fn unit_price(costs: Decimal, amount: Decimal, field: &'static str) -> Result<Decimal, CryptoTaxError> {
+    match amount > Decimal::ZERO {
+        true => Ok((costs / amount).normalize()),
+        false => Err(CryptoTaxError::InvalidAmount(field)),
+    }
+}
 
-    calculation.log
+fn required<T>(value: Option<T>, field: &'static str) -> Result<T, CryptoTaxError> {
+    value.ok_or(CryptoTaxError::MissingField(field))
 }
 
+fn withdraw_from(
+    inventory: &mut Inventory,
+    asset: &str,
+    amount: Decimal,
+    lots: &[u32],
+) -> Result<Vec<InventoryChange>, CryptoTaxError> {
+    inventory
+        .withdraw(amount, lots)
+        .map_err(|err| match err {
+            WithdrawalError::InsufficientFunds(missing) => CryptoTaxError::InsufficientFunds {
+                asset: asset.to_string(),
+                missing,
+            },
+            WithdrawalError::UnknownLot(tx_in) => CryptoTaxError::UnknownLot {
+                asset: asset.to_string(),
+                tx_in,
+            },
+        })
+}
+
+/// Inventories are kept per asset, and optionally per account: (account, asset)
+type InventoryKey = (Option<String>, String);
+
 struct CapitalGainsCalculation<'a> {
     price_information: &'a mut PriceInformation,
-    assets: HashMap<String, Inventory>,
+    settings: &'a Settings<'a>,
+    assets: HashMap<InventoryKey, Inventory>,
+    lent: HashMap<(String, String), Inventory>,
+    /// amounts lent under the disposal treatment, per (account, asset); their disposal is realized already
+    lent_disposed: HashMap<(String, String), Decimal>,
     log: Vec<CashflowRecord>,
-    accounting_method: AccountingMethod,
-    base_asset: &'a str,
-    currency_precision: f64,
+    positions: PositionLedger,
+    base_balance: Decimal,
+    uk_pooling: Option<UkPooling>,
 }
 
 impl<'a> CapitalGainsCalculation<'a> {
     fn new(
         price_information: &'a mut PriceInformation,
-        accounting_method: AccountingMethod,
-        base_asset: &'a str,
-        currency_precision: f64,
+        settings: &'a Settings<'a>,
     ) -> CapitalGainsCalculation<'a> {
         CapitalGainsCalculation {
             price_information,
-            accounting_method,
-            base_asset,
-            currency_precision,
+            settings,
+            base_balance: Decimal::ZERO,
             assets: HashMap::new(),
+            lent: HashMap::new(),
+            lent_disposed: HashMap::new(),
             log: Vec::new(),
+            positions: PositionLedger::default(),
+            uk_pooling: match settings.accounting_method {
+                AccountingMethod::UkPooling => Some(UkPooling::default()),
+                _ => None,
+            },
         }
     }
 
-    fn process_record(&mut self, row: TransactionRecord) {
-        match row.tx_type.as_str() {
-            "Trade" => {
-                let out_asset = row.out_asset.clone().unwrap();
-                if row.in_asset != out_asset {
-                    if out_asset == self.base_asset || row.in_asset == self.base_asset {
-                        self.process_trade_simple(row);
+    fn round(&self, value: Decimal) -> Decimal {
+        round(value, self.settings.currency_precision)
+    }
+
+    fn inventory(&mut self, account: &str, asset: &str) -> &mut Inventory {
+        let key = match self.settings.inventory_scope {
+            InventoryScope::Global => (None, asset.to_string()),
+            InventoryScope::PerAccount => (Some(account.to_string()), asset.to_string()),
+        };
+        let accounting_method = self.settings.accounting_method;
+        self.assets
+            .entry(key)
+            .or_insert_with(|| Inventory::new(accounting_method))
+    }
+
+    fn withdraw(
+        &mut self,
+        account: &str,
+        asset: &str,
+        amount: Decimal,
+        lots: &[u32],
+    ) -> Result<Vec<InventoryChange>, CryptoTaxError> {
+        withdraw_from(self.inventory(account, asset), asset, amount, lots)
+    }
+
+    fn lent_inventory(&mut self, account: &str, asset: &str) -> &mut Inventory {
+        // lent assets are kept per account (the lending platform), apart from the spot inventories
+        let accounting_method = self.settings.accounting_method;
+        self.lent
+            .entry((account.to_string(), asset.to_string()))
+            .or_insert_with(|| Inventory::new(accounting_method))
+    }
+
+    fn process_record(&mut self, row: TransactionRecord) -> Result<(), CryptoTaxError> {
+        self.update_base_balance(&row);
+
+        match row.kind {
+            TransactionKind::Trade => {
+                let out_asset = required(row.out_asset.clone(), "out_asset")?;
+                let in_asset = required(row.in_asset.clone(), "in_asset")?;
+                if in_asset != out_asset {
+                    if out_asset == self.settings.base_asset || in_asset == self.settings.base_asset {
+                        self.process_trade_simple(row)?;
                     } else {
-                        self.process_trade_foreign_to_foreign(row);
+                        self.process_trade_foreign_to_foreign(row)?;
                     }
                 }
             },
-            "Staking Reward" => {
-                self.process_staking_reward(row);
+            TransactionKind::StakingReward => {
+                self.process_income(row, IncomeKind::Staking, INCOME_AT_MARKET)?;
+            },
+            TransactionKind::LendingInterest => {
+                self.process_income(row, IncomeKind::LendingInterest, INCOME_AT_MARKET)?;
+            },
+            TransactionKind::Airdrop => {
+                let treatment = self.settings.receipts.airdrop;
+                self.process_income(row, IncomeKind::Airdrop, treatment)?;
+            },
+            TransactionKind::Fork => {
+                let treatment = self.settings.receipts.fork;
+                self.process_income(row, IncomeKind::Fork, treatment)?;
+            },
+            TransactionKind::Mining => {
+                let treatment = self.settings.receipts.mining;
+                self.process_income(row, IncomeKind::Mining, treatment)?;
+            },
+            TransactionKind::Deposit => {
+                self.process_deposit(row)?;
+            },
+            TransactionKind::Withdrawal => {
+                self.process_external_withdrawal(row)?;
+            },
+            TransactionKind::Transfer => {
+                self.process_transfer(row)?;
+            },
+            TransactionKind::Gift => {
+                let treatment = self.settings.disposals.gift;
+                self.process_disposal(row, DisposalKind::Gift, treatment)?;
+            },
+            TransactionKind::GiftReceived => {
+                self.process_gift_received(row)?;
+            },
+            TransactionKind::Donation => {
+                let treatment = self.settings.disposals.donation;
+                self.process_disposal(row, DisposalKind::Donation, treatment)?;
+            },
+            TransactionKind::Loss => {
+                let treatment = self.settings.disposals.loss;
+                self.process_disposal(row, DisposalKind::Loss, treatment)?;
+            },
+            TransactionKind::Spend => {
+                // paying for goods and services disposes of the asset at its market value
+                self.process_disposal(row, DisposalKind::Spend, DisposalTreatment::AtMarket)?;
+            },
+            TransactionKind::Fee => {
+                self.process_standalone_fee(row)?;
+            },
+            TransactionKind::FuturesOpen => {
+                self.process_position(row, true)?;
+            },
+            TransactionKind::FuturesClose => {
+                self.process_position(row, false)?;
+            },
+            TransactionKind::RealizedPnl | TransactionKind::Funding => {
+                self.process_settlement(row)?;
+            },
+            TransactionKind::Lend => {
+                self.process_lending(row, true)?;
+            },
+            TransactionKind::LendReturn => {
+                self.process_lending(row, false)?;
+            },
+            TransactionKind::LoanDrawdown => {
+                // borrowed assets are no income; they are received like a deposit, at market price
+                self.process_deposit(row)?;
+            },
+            TransactionKind::LoanRepayment => {
+                // repaid assets leave the inventory like a withdrawal, without gains
+                self.process_external_withdrawal(row)?;
+            },
+            TransactionKind::Liquidation => {
+                self.process_liquidation(row)?;
+            },
+            TransactionKind::Mint => {
+                self.process_mint(row)?;
+            },
+            TransactionKind::LiquidityAdd => {
+                self.process_liquidity_add(vec![row])?;
+            },
+            TransactionKind::LiquidityRemove => {
+                self.process_liquidity_remove(vec![row])?;
             },
-            _ => {
-                panic!("Unknown transaction type")
-            }
         };
+
+        self.check_base_balance()
+    }
+
+    fn process_legs(&mut self, mut rows: Vec<TransactionRecord>, line: u64) -> Result<(), CryptoTaxError> {
+        // process all rows of a multi-leg transaction at once;
+        // errors refer to the first row of the transaction
+        let tx_id = rows[0].tx_id;
+        let kind = rows[0].kind;
+        if rows.len() == 1 {
+            return self.process_record(rows.remove(0)).map_err(|source| CryptoTaxError::Transaction {
+                tx_id,
+                line,
+                source: Box::new(source),
+            });
+        }
+
+        for row in rows.iter() {
+            self.update_base_balance(row);
+        }
+
+        match kind {
+            TransactionKind::Trade => self.process_trade_multi_leg(rows),
+            TransactionKind::LiquidityAdd => self.process_liquidity_add(rows),
+            TransactionKind::LiquidityRemove => self.process_liquidity_remove(rows),
+            // only the rows of multi-leg kinds are collected
+            _ => Ok(()),
+        }
+            .and_then(|_| self.check_base_balance())
+            .map_err(|source| CryptoTaxError::Transaction {
+                tx_id,
+                line,
+                source: Box::new(source),
+            })
+    }
+
+    fn check_base_balance(&self) -> Result<(), CryptoTaxError> {
+        if self.settings.check_base_balance && self.base_balance < Decimal::ZERO {
+            return Err(CryptoTaxError::InsufficientFunds {
+                asset: self.settings.base_asset.to_string(),
+                missing: -self.base_balance,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn update_base_balance(&mut self, row: &TransactionRecord) {
+        // the base asset is not kept in an inventory; track its balance from the actual cash flows;
+        // transfers between accounts leave the balance unchanged, except for their fees
+        if row.kind != TransactionKind::Transfer {
+            if let (Some(asset), Some(amount)) = (&row.out_asset, row.out_amount) {
+                if asset == self.settings.base_asset {
+                    self.base_balance -= amount;
+                }
+            }
+            if let (Some(asset), Some(amount)) = (&row.in_asset, row.in_amount) {
+                if asset == self.settings.base_asset {
+                    self.base_balance += amount;
+                }
+            }
+        }
+        if let (Some(asset), Some(amount)) = (&row.fee_asset, row.fee_amount) {
+            if asset == self.settings.base_asset {
+                self.base_balance -= amount;
+            }
+        }
     }
 
     fn process_trade_simple(
         &mut self,
         record: TransactionRecord
-    ) {
+    ) -> Result<(), CryptoTaxError> {
         // a simple trade involves the base asset; it constitutes either:
         //  A) a FOREX purchase (base to foreign)
         //  B) a FOREX sale (foreign to base)
 
         // extract trade parameters
-        let out_asset = record.out_asset.unwrap();
-        let in_asset = record.in_asset;
-        let mut out_amount = record.out_amount.unwrap();
-        let mut in_amount = record.in_amount;
+        let out_asset = required(record.out_asset, "out_asset")?;
+        let in_asset = required(record.in_asset, "in_asset")?;
+        let mut out_amount = required(record.out_amount, "out_amount")?;
+        let mut in_amount = required(record.in_amount, "in_amount")?;
 
         // trade must involve the base asset and one foreign asset
-        if (in_asset != self.base_asset && out_asset != self.base_asset)
+        if (in_asset != self.settings.base_asset && out_asset != self.settings.base_asset)
             || (in_asset == out_asset)
         {
-            return;
+            return Ok(());
         }
 
         // calculate fees, if any
@@ -117,69 +491,90 @@ impl<'a> CapitalGainsCalculation<'a> {
                 in_amount -= fee_amount;
             } else {
                 // convert fee into base asset and attribute to base_asset
-                let fee_base_value = self.process_withdrawal(&fee_asset, Withdrawal {
+                let fee_base_value = self.process_withdrawal(&record.account, &fee_asset, Withdrawal {
                     tx_id: record.tx_id,
                     datetime: record.datetime,
                     amount: fee_amount,
-                });
-                if out_asset == self.base_asset {
+                    lots: Vec::new(),
+                    disposal: None,
+                })?;
+                if out_asset == self.settings.base_asset {
                     out_amount += fee_base_value;
-                } else if in_asset == self.base_asset {
+                } else if in_asset == self.settings.base_asset {
                     in_amount -= fee_base_value;
                 }
             }
         }
 
         // process the purchase / sale
-        if out_asset == self.base_asset {
-            self.process_purchase(&in_asset, Purchase {
+        if out_asset == self.settings.base_asset {
+            self.process_purchase(&record.account, &in_asset, Purchase {
                 tx_id: record.tx_id,
                 datetime: record.datetime,
                 amount: in_amount,
-                base_price: out_amount / in_amount,
+                base_price: unit_price(out_amount, in_amount, "in_amount")?,
                 actual_costs: out_amount,
-            });
-        } else if in_asset == self.base_asset {
-            self.process_sale(&out_asset, Sale {
+                income: None,
+            })?;
+        } else if in_asset == self.settings.base_asset {
+            self.process_sale(&record.account, &out_asset, Sale {
                 tx_id: record.tx_id,
                 datetime: record.datetime,
                 amount: out_amount,
                 proceeds: in_amount,
-            });
+                lots: record.lots,
+                disposal: None,
+            })?;
         }
+
+        Ok(())
     }
 
     fn process_trade_foreign_to_foreign(
         &mut self,
         record: TransactionRecord
-    ) {
+    ) -> Result<(), CryptoTaxError> {
         // sell a foreign asset to receive a foreign asset;
         // we need to split this up into two transactions:
         //  1) sell foreign asset for base asset, at market price
         //  2) sell base asset for the other foreign asset
 
         // unwrap out asset
-        let out_asset = record.out_asset.clone().unwrap();
-        let out_amount = record.out_amount.unwrap();
-
-         // query market price for the asset that we'd like to dispose of
-        let out_base_price = self.price_information.get(
-            &out_asset,
-            &self.base_asset,
-            record.datetime);
+        let out_asset = required(record.out_asset.clone(), "out_asset")?;
+        let out_amount = required(record.out_amount, "out_amount")?;
 
-        // calculate value (in base asset terms) of the transaction, net of fees
-        let out_base_value = out_base_price * out_amount;
+        // calculate value (in base asset terms) of the transaction, net of fees;
+        // a non-fungible token has no market price, so a token sold e.g. for ETH is valued at the received asset
+        let out_base_value = match is_token(&out_asset) {
+            true => {
+                let in_asset = required(record.in_asset.clone(), "in_asset")?;
+                let in_amount = required(record.in_amount, "in_amount")?;
+                let in_base_price = self.price_information.get(
+                    &in_asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                self.round(in_base_price * in_amount)
+            },
+            false => {
+                // query market price for the asset that we'd like to dispose of
+                let out_base_price = self.price_information.get(
+                    &out_asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                self.round(out_base_price * out_amount)
+            },
+        };
 
         // distribute fees, if any
         let (sale_fee_amount, purchase_fee_amount) = match (record.fee_asset.clone(), record.fee_amount) {
             (Some(fee_asset), Some(fee_amount)) => {
                 if fee_asset == out_asset {
-                    (Some(fee_amount), Some(0f64))
-                } else if fee_asset == record.in_asset {
-                    (Some(0f64), Some(fee_amount))
+                    (Some(fee_amount), Some(Decimal::ZERO))
+                } else if Some(&fee_asset) == record.in_asset.as_ref() {
+                    (Some(Decimal::ZERO), Some(fee_amount))
                 } else {
-                    (Some(fee_amount / 2f64), Some(fee_amount / 2f64))
+                    let sale_fee_amount = fee_amount / Decimal::TWO;
+                    (Some(sale_fee_amount), Some(fee_amount - sale_fee_amount))
                 }
             },
             _ => (None, None),
@@ -189,167 +584,1243 @@ impl<'a> CapitalGainsCalculation<'a> {
         self.process_trade_simple(TransactionRecord {
             out_asset: record.out_asset.clone(),
             out_amount: record.out_amount,
-            in_asset: self.base_asset.to_string(),
-            in_amount: out_base_value,
+            in_asset: Some(self.settings.base_asset.to_string()),
+            in_amount: Some(out_base_value),
             fee_asset: record.fee_asset.clone(),
             fee_amount: sale_fee_amount,
             ..record.clone()
-        });
+        })?;
 
         // buy in_asset with base_asset
         self.process_trade_simple(TransactionRecord {
-            out_asset: Some(self.base_asset.to_string()),
+            out_asset: Some(self.settings.base_asset.to_string()),
             out_amount: Some(out_base_value),
             in_asset: record.in_asset.clone(),
             in_amount: record.in_amount,
             fee_asset: record.fee_asset.clone(),
             fee_amount: purchase_fee_amount,
             ..record.clone()
-        });
+        })
+    }
+
+    fn process_trade_multi_leg(
+        &mut self,
+        rows: Vec<TransactionRecord>
+    ) -> Result<(), CryptoTaxError> {
+        // exchange several assets for several others, e.g. a DeFi swap with more than one output;
+        // each row may dispose of one asset and receive another; all disposed assets are sold
+        // at market value, and their total value is the cost of the received assets,
+        // allocated to them in proportion to their market values
+
+        let record = &rows[0];
+
+        // collect the fees, like in a simple trade: fees in the base asset add to the costs,
+        // fees in a disposed asset are sold along with it, fees in a received asset are deducted
+        // from its received amount, and fees in another asset are withdrawn and add their costs
+        let mut fees = Fees::collect(&rows, self.settings.base_asset);
+
+        // sell the disposed assets
+        let mut sales: Vec<(String, Decimal, Decimal, Vec<u32>)> = Vec::new();
+        for row in rows.iter() {
+            if let (Some(out_asset), Some(out_amount)) = (&row.out_asset, row.out_amount) {
+                sales.push((out_asset.clone(), out_amount, fees.take(out_asset), row.lots.clone()));
+            }
+        }
+
+        // determine the market values of the received assets, and their amounts net of fees
+        let mut legs: Vec<(String, Decimal, Decimal)> = Vec::new();
+        for row in rows.iter() {
+            if let (Some(in_asset), Some(in_amount)) = (&row.in_asset, row.in_amount) {
+                let base_price = match in_asset == self.settings.base_asset {
+                    true => Decimal::ONE,
+                    false => self.price_information.get(
+                        in_asset,
+                        self.settings.base_asset,
+                        record.datetime)?,
+                };
+                legs.push((in_asset.clone(), in_amount - fees.take(in_asset), self.round(in_amount * base_price)));
+            }
+        }
+
+        let mut total_costs = self.withdraw_fees(record, fees)?;
+
+        for (out_asset, out_amount, fee_amount, lots) in sales {
+            if out_asset == self.settings.base_asset {
+                total_costs += out_amount;
+                continue;
+            }
+
+            // the fee is sold along with the disposed amount, without proceeds of its own
+
+            let base_price = self.price_information.get(
+                &out_asset,
+                self.settings.base_asset,
+                record.datetime)?;
+            let proceeds = self.round(base_price * out_amount);
+            self.process_sale(&record.account, &out_asset, Sale {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount: out_amount + fee_amount,
+                proceeds,
+                lots,
+                disposal: None,
+            })?;
+            total_costs += proceeds;
+        }
+
+        // buy the received assets, with the costs split by market value
+        let values: Vec<Decimal> = legs.iter().map(|(_, _, value)| *value).collect();
+        let leg_costs = allocate(total_costs, &values, |share| self.round(share));
+        for ((asset, amount, _), costs) in legs.into_iter().zip(leg_costs) {
+            if asset == self.settings.base_asset {
+                continue;
+            }
+            self.process_purchase(&record.account, &asset, Purchase {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount,
+                base_price: unit_price(costs, amount, "in_amount")?,
+                actual_costs: costs,
+                income: None,
+            })?;
+        }
+
+        Ok(())
     }
 
-    fn process_staking_reward(
+    fn process_income(
+        &mut self,
+        record: TransactionRecord,
+        kind: IncomeKind,
+        treatment: ReceiptTreatment,
+    ) -> Result<(), CryptoTaxError> {
+        // receive an asset without a purchase, e.g. a staking reward or the coins of a hard fork;
+        // record the inflow at the configured cost basis, and its market value as income, if configured
+
+        let in_asset = required(record.in_asset.clone(), "in_asset")?;
+        let in_amount = required(record.in_amount, "in_amount")?;
+
+        // query market price of inflowing asset
+        let market_price = self.price_information.get(
+            &in_asset,
+            self.settings.base_asset,
+            record.datetime)?;
+
+        // determine the lots to record; only a split from the parent asset results in more than one lot
+        let layers = match treatment.cost_basis {
+            CostBasis::Zero | CostBasis::Market => Vec::new(),
+            CostBasis::Split => self.split_from_parent(&record, in_amount, market_price)?,
+        };
+        let layers = match layers.is_empty() {
+            true => vec![InventoryChange {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount: in_amount,
+                base_price: match treatment.cost_basis {
+                    CostBasis::Market => market_price,
+                    _ => Decimal::ZERO,
+                },
+            }],
+            false => layers,
+        };
+
+        // record the inflow
+        for layer in layers {
+            let income = match treatment.income {
+                true => Some(Income { kind, value: layer.amount * market_price }),
+                false => None,
+            };
+            self.process_purchase(&record.account, &in_asset, Purchase {
+                tx_id: layer.tx_id,
+                datetime: layer.datetime,
+                amount: layer.amount,
+                base_price: layer.base_price,
+                actual_costs: match income {
+                    Some(_) => Decimal::ZERO,
+                    None => layer.amount * layer.base_price,
+                },
+                income,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn split_from_parent(
+        &mut self,
+        record: &TransactionRecord,
+        amount: Decimal,
+        market_price: Decimal,
+    ) -> Result<Vec<InventoryChange>, CryptoTaxError> {
+        // the costs of the parent asset are split between the parent and the received asset,
+        // in proportion to their market values at the time of receipt
+
+        let parent_asset = required(record.out_asset.clone(), "out_asset")?;
+        let parent_price = self.price_information.get(
+            &parent_asset,
+            self.settings.base_asset,
+            record.datetime)?;
+
+        let parent = self.inventory(&record.account, &parent_asset);
+        let value = amount * market_price;
+        let total_value = value + parent.balance() * parent_price;
+        if total_value.is_zero() {
+            return Ok(Vec::new());
+        }
+
+        let layers = parent.split(amount, value / total_value);
+
+        // with UK share pooling, the split-off costs leave the parent's Section 104 pool as well
+        if let Some(uk_pooling) = &mut self.uk_pooling {
+            let costs = layers.iter().map(|layer| layer.amount * layer.base_price).sum();
+            uk_pooling.split(&parent_asset, costs);
+        }
+
+        Ok(layers)
+    }
+
+    fn process_deposit(
         &mut self,
         record: TransactionRecord
-    ) {
-        // receive an asset without any costs;
-        // record the inflow at the asset's market price
+    ) -> Result<(), CryptoTaxError> {
+        // receive an asset from outside of the tracked accounts (e.g. a bank transfer);
+        // a deposit of the base asset only increases the base balance,
+        // a deposit of a foreign asset is recorded at market price, without any gains
+
+        let in_asset = required(record.in_asset.clone(), "in_asset")?;
+        let in_amount = required(record.in_amount, "in_amount")?
+            - self.process_fee(&record, &in_asset)?;
+
+        if in_asset == self.settings.base_asset {
+            return Ok(());
+        }
 
         // query market price of inflowing asset
-        let reward_base_price = self.price_information.get(
-            record.in_asset.as_str(),
-            &self.base_asset,
-            record.datetime);
+        let base_price = self.price_information.get(
+            &in_asset,
+            self.settings.base_asset,
+            record.datetime)?;
 
         // record the inflow
-        self.process_purchase(&record.in_asset, Purchase {
+        self.process_purchase(&record.account, &in_asset, Purchase {
             tx_id: record.tx_id,
             datetime: record.datetime,
-            amount: record.in_amount,
-            base_price: reward_base_price,
-            actual_costs: 0f64,
+            amount: in_amount,
+            base_price,
+            actual_costs: in_amount * base_price,
+            income: None,
+        })
+    }
+
+    fn process_gift_received(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // receive an asset as a gift; the donor's costs and acquisition date carry over,
+        // so that the holding period of the gifted lot starts when the donor acquired it;
+        // if they are not given, the asset is recorded at market price, as of the receipt
+
+        let in_asset = required(record.in_asset.clone(), "in_asset")?;
+        let in_amount = required(record.in_amount, "in_amount")?
+            - self.process_fee(&record, &in_asset)?;
+
+        if in_asset == self.settings.base_asset {
+            return Ok(());
+        }
+
+        let costs = match record.acquisition_costs {
+            Some(costs) => costs,
+            None => in_amount * self.price_information.get(
+                &in_asset,
+                self.settings.base_asset,
+                record.datetime)?,
+        };
+
+        self.process_purchase(&record.account, &in_asset, Purchase {
+            tx_id: record.tx_id,
+            datetime: record.acquisition_datetime.unwrap_or(record.datetime),
+            amount: in_amount,
+            base_price: unit_price(costs, in_amount, "in_amount")?,
+            actual_costs: costs,
+            income: None,
+        })
+    }
+
+    fn process_external_withdrawal(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // send an asset to a destination outside of the tracked accounts (e.g. a bank account);
+        // a withdrawal of the base asset only decreases the base balance,
+        // a withdrawal of a foreign asset is gain-neutral
+
+        let out_asset = required(record.out_asset.clone(), "out_asset")?;
+        let out_amount = required(record.out_amount, "out_amount")?
+            + self.process_fee(&record, &out_asset)?;
+
+        if out_asset == self.settings.base_asset {
+            return Ok(());
+        }
+
+        self.process_withdrawal(&record.account, &out_asset, Withdrawal {
+            tx_id: record.tx_id,
+            datetime: record.datetime,
+            amount: out_amount,
+            lots: record.lots,
+            disposal: None,
+        })?;
+
+        Ok(())
+    }
+
+    fn process_transfer(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // move an asset from one account to another; a transfer is not a disposal:
+        // the transferred layers keep their costs and acquisition dates,
+        // and the network fee, if any, is withdrawn gain-neutrally from the source account
+
+        let out_asset = required(record.out_asset.clone(), "out_asset")?;
+        let out_amount = required(record.out_amount, "out_amount")?;
+        let destination_account = required(record.destination_account.clone(), "destination_account")?;
+
+        // withdraw the network fee
+        self.process_fee(&record, "")?;
+
+        if out_asset == self.settings.base_asset || destination_account == record.account {
+            return Ok(());
+        }
+
+        // take the layers out of the source account's inventory
+        let layers = self.withdraw(&record.account, &out_asset, out_amount, &record.lots)?;
+
+        // put the layers into the destination account's inventory, sorted by their acquisition dates;
+        // with a global inventory scope, this is the inventory that they were taken from
+        let destination = self.inventory(&destination_account, &out_asset);
+        for layer in layers {
+            destination.deposit(layer);
+        }
+
+        Ok(())
+    }
+
+    fn process_lending(
+        &mut self,
+        record: TransactionRecord,
+        lending: bool,
+    ) -> Result<(), CryptoTaxError> {
+        // lend an asset to a lending platform, or receive it back; as configured, lending is either
+        // no disposal, so that the lent layers keep their costs and acquisition dates,
+        // or a disposal at market value, and the return a new acquisition at market value;
+        // the fee, if any, is withdrawn gain-neutrally
+
+        let (asset, amount) = match lending {
+            true => (required(record.out_asset.clone(), "out_asset")?, required(record.out_amount, "out_amount")?),
+            false => (required(record.in_asset.clone(), "in_asset")?, required(record.in_amount, "in_amount")?),
+        };
+
+        self.process_fee(&record, "")?;
+
+        if asset == self.settings.base_asset {
+            return Ok(());
+        }
+
+        match (self.settings.lending, lending) {
+            (LendingTreatment::NonDisposal, true) => {
+                // move the layers into the account's inventory of lent assets
+                let layers = self.withdraw(&record.account, &asset, amount, &record.lots)?;
+                let lent = self.lent_inventory(&record.account, &asset);
+                for layer in layers {
+                    lent.deposit(layer);
+                }
+            },
+            (LendingTreatment::NonDisposal, false) => {
+                // move the layers back into the spot inventory
+                let lent = self.lent_inventory(&record.account, &asset);
+                let layers = withdraw_from(lent, &asset, amount, &record.lots)?;
+                let inventory = self.inventory(&record.account, &asset);
+                for layer in layers {
+                    inventory.deposit(layer);
+                }
+            },
+            (LendingTreatment::Disposal, true) => {
+                let base_price = self.price_information.get(
+                    &asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                self.process_sale(&record.account, &asset, Sale {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount,
+                    proceeds: self.round(base_price * amount),
+                    lots: record.lots,
+                    disposal: None,
+                })?;
+                *self.lent_disposed.entry((record.account, asset)).or_default() += amount;
+            },
+            (LendingTreatment::Disposal, false) => {
+                let disposed = self.lent_disposed.entry((record.account.clone(), asset.clone())).or_default();
+                *disposed -= amount.min(*disposed);
+                let base_price = self.price_information.get(
+                    &asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                self.process_purchase(&record.account, &asset, Purchase {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount,
+                    base_price,
+                    actual_costs: amount * base_price,
+                    income: None,
+                })?;
+            },
+        };
+
+        Ok(())
+    }
+
+    fn process_mint(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // mint a token: the mint price (the disposed asset, if any) and the gas (the fee) are disposed of
+        // at their market value, and their total value is capitalized into the costs of the minted token
+
+        let in_asset = required(record.in_asset.clone(), "in_asset")?;
+        let in_amount = required(record.in_amount, "in_amount")?;
+
+        let mut payments: Vec<(String, Decimal, Vec<u32>)> = Vec::new();
+        if let (Some(out_asset), Some(out_amount)) = (&record.out_asset, record.out_amount) {
+            payments.push((out_asset.clone(), out_amount, record.lots.clone()));
+        }
+        if let (Some(fee_asset), Some(fee_amount)) = (&record.fee_asset, record.fee_amount) {
+            match payments.iter_mut().find(|(asset, _, _)| asset == fee_asset) {
+                Some(payment) => payment.1 += fee_amount,
+                None => payments.push((fee_asset.clone(), fee_amount, Vec::new())),
+            };
+        }
+
+        let mut costs = Decimal::ZERO;
+        for (asset, amount, lots) in payments {
+            if asset == self.settings.base_asset {
+                costs += amount;
+                continue;
+            }
+
+            let base_price = self.price_information.get(
+                &asset,
+                self.settings.base_asset,
+                record.datetime)?;
+            let value = self.round(base_price * amount);
+            self.process_sale(&record.account, &asset, Sale {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount,
+                proceeds: value,
+                lots,
+                disposal: None,
+            })?;
+            costs += value;
+        }
+
+        self.process_purchase(&record.account, &in_asset, Purchase {
+            tx_id: record.tx_id,
+            datetime: record.datetime,
+            amount: in_amount,
+            base_price: unit_price(costs, in_amount, "in_amount")?,
+            actual_costs: costs,
+            income: None,
+        })
+    }
+
+    fn process_liquidation(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // the seized collateral is disposed of at its market value; collateral that was lent
+        // without a disposal is taken from the lent assets first, and the rest from the spot inventory;
+        // collateral that was lent as a disposal has left the inventory already, and is not disposed of again;
+        // the fee, if any, is withdrawn gain-neutrally
+
+        let out_asset = required(record.out_asset.clone(), "out_asset")?;
+        let out_amount = required(record.out_amount, "out_amount")?;
+        self.process_fee(&record, "")?;
+
+        if out_asset == self.settings.base_asset {
+            return Ok(());
+        }
+
+        let disposed = self.lent_disposed.entry((record.account.clone(), out_asset.clone())).or_default();
+        let covered = out_amount.min(*disposed);
+        *disposed -= covered;
+        let out_amount = out_amount - covered;
+        if out_amount.is_zero() {
+            return Ok(());
+        }
+
+        let base_price = self.price_information.get(
+            &out_asset,
+            self.settings.base_asset,
+            record.datetime)?;
+        let proceeds = self.round(base_price * out_amount);
+
+        // lots named for specific identification refer to the lent collateral
+        let lent = self.lent_inventory(&record.account, &out_asset);
+        let lent_amount = out_amount.min(lent.balance());
+        let mut outflows = withdraw_from(lent, &out_asset, lent_amount, &record.lots)?;
+        if out_amount > lent_amount {
+            outflows.extend(self.withdraw(&record.account, &out_asset, out_amount - lent_amount, &[])?);
+        }
+
+        self.realize_sale(&record.account, &out_asset, Sale {
+            tx_id: record.tx_id,
+            datetime: record.datetime,
+            amount: out_amount,
+            proceeds,
+            lots: record.lots,
+            disposal: Some(Disposal {
+                kind: DisposalKind::Liquidation,
+                reason: record.reason,
+                description: record.description,
+                expense: false,
+            }),
+        }, outflows)
+    }
+
+    fn process_disposal(
+        &mut self,
+        record: TransactionRecord,
+        kind: DisposalKind,
+        treatment: DisposalTreatment,
+    ) -> Result<(), CryptoTaxError> {
+        // give away, spend or lose an asset, e.g. a gift, a card payment or coins lost in a hack;
+        // the assets leave the inventory at cost, at market value, or without proceeds, as configured;
+        // fees are withdrawn gain-neutrally, since they are not part of the disposal
+
+        let out_asset = required(record.out_asset.clone(), "out_asset")?;
+        let out_amount = required(record.out_amount, "out_amount")?;
+        let fee_amount = self.process_fee(&record, &out_asset)?;
+
+        if out_asset == self.settings.base_asset {
+            return Ok(());
+        }
+
+        if !fee_amount.is_zero() {
+            self.process_withdrawal(&record.account, &out_asset, Withdrawal {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount: fee_amount,
+                lots: Vec::new(),
+                disposal: None,
+            })?;
+        }
+
+        let disposal = Some(Disposal {
+            kind,
+            reason: record.reason,
+            description: record.description,
+            expense: false,
+        });
+
+        let proceeds = match treatment {
+            DisposalTreatment::AtCost => {
+                self.process_withdrawal(&record.account, &out_asset, Withdrawal {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: out_amount,
+                    lots: record.lots,
+                    disposal,
+                })?;
+                return Ok(());
+            },
+            DisposalTreatment::AtMarket => {
+                let base_price = self.price_information.get(
+                    &out_asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                self.round(base_price * out_amount)
+            },
+            DisposalTreatment::RealizedLoss => Decimal::ZERO,
+        };
+
+        self.process_sale(&record.account, &out_asset, Sale {
+            tx_id: record.tx_id,
+            datetime: record.datetime,
+            amount: out_amount,
+            proceeds,
+            lots: record.lots,
+            disposal,
+        })
+    }
+
+    fn process_standalone_fee(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // pay a fee that is not part of another transaction, e.g. a subscription fee or gas;
+        // the fee is withdrawn at cost, disposed of at market value, or deducted as an expense, as configured
+
+        let fee_asset = required(record.fee_asset.clone(), "fee_asset")?;
+        let fee_amount = required(record.fee_amount, "fee_amount")?;
+        let treatment = self.settings.fees;
+
+        let disposal = Some(Disposal {
+            kind: DisposalKind::Fee,
+            reason: record.reason,
+            description: record.description,
+            expense: matches!(treatment, FeeTreatment::Expense),
+        });
+
+        // fees in the base asset only decrease the base balance, unless they are an expense
+        if fee_asset == self.settings.base_asset {
+            if let FeeTreatment::Expense = treatment {
+                let costs = self.round(fee_amount);
+                self.log.push(CashflowRecord {
+                    account: record.account.clone(),
+                    asset: fee_asset,
+                    tx_out: Some(record.tx_id),
+                    datetime_out: Some(record.datetime),
+                    tx_in: None,
+                    datetime_in: None,
+                    amount: -fee_amount,
+                    base_price: Decimal::ONE,
+                    actual_costs: costs,
+                    actual_proceeds: None,
+                    gains_short_term: None,
+                    gains_long_term: None,
+                    holding_period: None,
+                    matching_rule: None,
+                    income: None,
+                    income_value: None,
+                    derivative_income: None,
+                    disposal: Some(DisposalKind::Fee),
+                    reason: disposal.as_ref().and_then(|disposal| disposal.reason.clone()),
+                    description: disposal.as_ref().and_then(|disposal| disposal.description.clone()),
+                    expense: Some(costs),
+                });
+            }
+            return Ok(());
+        }
+
+        match treatment {
+            FeeTreatment::Neutral | FeeTreatment::Expense => {
+                self.process_withdrawal(&record.account, &fee_asset, Withdrawal {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: fee_amount,
+                    lots: record.lots,
+                    disposal,
+                })?;
+            },
+            FeeTreatment::Disposal => {
+                let base_price = self.price_information.get(
+                    &fee_asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                self.process_sale(&record.account, &fee_asset, Sale {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: fee_amount,
+                    proceeds: self.round(base_price * fee_amount),
+                    lots: record.lots,
+                    disposal,
+                })?;
+            },
+        };
+
+        Ok(())
+    }
+
+    fn process_position(
+        &mut self,
+        record: TransactionRecord,
+        opening: bool,
+    ) -> Result<(), CryptoTaxError> {
+        // open or close a derivative position; this does not move any assets,
+        // but the trading fees are deducted from the derivative income
+
+        let (contract, size) = match opening {
+            true => (required(record.in_asset.clone(), "in_asset")?, required(record.in_amount, "in_amount")?),
+            false => (required(record.out_asset.clone(), "out_asset")?, required(record.out_amount, "out_amount")?),
+        };
+
+        if opening {
+            self.positions.open(&record.account, &contract, size);
+        } else {
+            self.positions
+                .close(&record.account, &contract, size)
+                .map_err(|missing| CryptoTaxError::InsufficientFunds {
+                    asset: contract.clone(),
+                    missing,
+                })?;
+        }
+
+        let fees = self.process_derivative_fee(&record)?;
+        let amount = if opening {size} else {-size};
+        self.log_derivative(&record, &contract, amount, -fees);
+
+        Ok(())
+    }
+
+    fn process_settlement(
+        &mut self,
+        record: TransactionRecord
+    ) -> Result<(), CryptoTaxError> {
+        // settle a derivative position, e.g. realized PnL or a perpetual funding payment;
+        // a profit is received, a loss is paid in the settlement asset; a received asset enters
+        // the spot inventory at market price, a paid asset is disposed of at market value, which may
+        // realize spot gains; the market value of the settlement is derivative income
+
+        let (settlement_asset, value) = match (record.in_asset.clone(), record.in_amount) {
+            (Some(in_asset), Some(in_amount)) => {
+                let value = match in_asset == self.settings.base_asset {
+                    true => in_amount,
+                    false => {
+                        let base_price = self.price_information.get(
+                            &in_asset,
+                            self.settings.base_asset,
+                            record.datetime)?;
+                        self.process_purchase(&record.account, &in_asset, Purchase {
+                            tx_id: record.tx_id,
+                            datetime: record.datetime,
+                            amount: in_amount,
+                            base_price,
+                            actual_costs: in_amount * base_price,
+                            income: None,
+                        })?;
+                        self.round(in_amount * base_price)
+                    },
+                };
+                (in_asset, value)
+            },
+            _ => {
+                let out_asset = required(record.out_asset.clone(), "out_asset")?;
+                let out_amount = required(record.out_amount, "out_amount")?;
+                let value = match out_asset == self.settings.base_asset {
+                    true => out_amount,
+                    false => {
+                        let base_price = self.price_information.get(
+                            &out_asset,
+                            self.settings.base_asset,
+                            record.datetime)?;
+                        let value = self.round(out_amount * base_price);
+                        self.process_sale(&record.account, &out_asset, Sale {
+                            tx_id: record.tx_id,
+                            datetime: record.datetime,
+                            amount: out_amount,
+                            proceeds: value,
+                            lots: record.lots.clone(),
+                            disposal: None,
+                        })?;
+                        value
+                    },
+                };
+                (out_asset, -value)
+            },
+        };
+
+        let fees = self.process_derivative_fee(&record)?;
+        let contract = record.contract.clone().unwrap_or(settlement_asset);
+        self.log_derivative(&record, &contract, Decimal::ZERO, value - fees);
+
+        Ok(())
+    }
+
+    fn process_derivative_fee(&mut self, record: &TransactionRecord) -> Result<Decimal, CryptoTaxError> {
+        // fees of derivative transactions are disposed of at market value; returns their value in the base asset
+        match (&record.fee_asset, record.fee_amount) {
+            (Some(fee_asset), Some(fee_amount)) if fee_asset == self.settings.base_asset => Ok(fee_amount),
+            (Some(fee_asset), Some(fee_amount)) => {
+                let base_price = self.price_information.get(
+                    fee_asset,
+                    self.settings.base_asset,
+                    record.datetime)?;
+                let value = self.round(fee_amount * base_price);
+                self.process_sale(&record.account, fee_asset, Sale {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: fee_amount,
+                    proceeds: value,
+                    lots: Vec::new(),
+                    disposal: None,
+                })?;
+                Ok(value)
+            },
+            _ => Ok(Decimal::ZERO),
+        }
+    }
+
+    fn log_derivative(&mut self, record: &TransactionRecord, contract: &str, amount: Decimal, income: Decimal) {
+        // opening a position is listed as an inflow of the contract, everything else as an outflow
+        let (tx_in, datetime_in, tx_out, datetime_out) = match amount > Decimal::ZERO {
+            true => (Some(record.tx_id), Some(record.datetime), None, None),
+            false => (None, None, Some(record.tx_id), Some(record.datetime)),
+        };
+
+        self.log.push(CashflowRecord {
+            account: record.account.clone(),
+            asset: contract.to_string(),
+            tx_out,
+            datetime_out,
+            tx_in,
+            datetime_in,
+            amount,
+            base_price: Decimal::ZERO,
+            actual_costs: Decimal::ZERO,
+            actual_proceeds: None,
+            gains_short_term: None,
+            gains_long_term: None,
+            holding_period: None,
+            matching_rule: None,
+            income: None,
+            income_value: None,
+            derivative_income: Some(self.round(income)),
+            disposal: None,
+            reason: None,
+            description: record.description.clone(),
+            expense: None,
         });
     }
 
-    pub fn process_purchase(&mut self, asset: &str, purchase: Purchase) {
-        let inventory = self.assets
-            .entry(asset.to_string())
-            .or_insert(Inventory::new(
-                self.accounting_method.clone(),
-                self.currency_precision));
+    fn process_liquidity_add(
+        &mut self,
+        rows: Vec<TransactionRecord>
+    ) -> Result<(), CryptoTaxError> {
+        // deposit several assets into a liquidity pool, and receive an LP token in return;
+        // each row disposes of one asset, and one of the rows names the received LP token;
+        // the deposited assets are either sold at market value (a taxable swap),
+        // or leave the inventory at cost, so that the LP token inherits their combined costs
+
+        let record = &rows[0];
+        let lp_row = rows.iter().find(|row| row.in_asset.is_some());
+        let lp_asset = required(lp_row.and_then(|row| row.in_asset.clone()), "in_asset")?;
+        let mut fees = Fees::collect(&rows, self.settings.base_asset);
+        let lp_amount = required(lp_row.and_then(|row| row.in_amount), "in_amount")? - fees.take(&lp_asset);
+
+        // fees in the LP token reduce the received amount; all other fees add to its costs
+        let mut costs = self.withdraw_fees(record, fees)?;
+        for row in rows.iter() {
+            let (out_asset, out_amount) = match (&row.out_asset, row.out_amount) {
+                (Some(out_asset), Some(out_amount)) => (out_asset, out_amount),
+                _ => continue,
+            };
+            if out_asset == self.settings.base_asset {
+                costs += out_amount;
+                continue;
+            }
+
+            costs += match self.settings.liquidity {
+                LiquidityTreatment::Taxable => {
+                    let base_price = self.price_information.get(
+                        out_asset,
+                        self.settings.base_asset,
+                        record.datetime)?;
+                    let proceeds = self.round(base_price * out_amount);
+                    self.process_sale(&record.account, out_asset, Sale {
+                        tx_id: record.tx_id,
+                        datetime: record.datetime,
+                        amount: out_amount,
+                        proceeds,
+                        lots: row.lots.clone(),
+                        disposal: None,
+                    })?;
+                    proceeds
+                },
+                LiquidityTreatment::NonTaxable => {
+                    self.process_withdrawal(&record.account, out_asset, Withdrawal {
+                        tx_id: record.tx_id,
+                        datetime: record.datetime,
+                        amount: out_amount,
+                        lots: row.lots.clone(),
+                        disposal: None,
+                    })?
+                },
+            };
+        }
+
+        self.process_purchase(&record.account, &lp_asset, Purchase {
+            tx_id: record.tx_id,
+            datetime: record.datetime,
+            amount: lp_amount,
+            base_price: unit_price(costs, lp_amount, "in_amount")?,
+            actual_costs: costs,
+            income: None,
+        })
+    }
+
+    fn process_liquidity_remove(
+        &mut self,
+        rows: Vec<TransactionRecord>
+    ) -> Result<(), CryptoTaxError> {
+        // return an LP token to a liquidity pool, and receive several assets in return;
+        // each row receives one asset, and one of the rows names the returned LP token;
+        // the LP token is either sold for the market value of the received assets (a taxable swap),
+        // or leaves the inventory at cost, which is split between the received assets by market value
+
+        let record = &rows[0];
+        let lp_row = rows.iter().find(|row| row.out_asset.is_some());
+        let lp_asset = required(lp_row.and_then(|row| row.out_asset.clone()), "out_asset")?;
+        let mut fees = Fees::collect(&rows, self.settings.base_asset);
+        let lp_amount = required(lp_row.and_then(|row| row.out_amount), "out_amount")? + fees.take(&lp_asset);
+
+        // determine the market values of the received assets, and their amounts net of fees
+        let mut legs: Vec<(String, Decimal)> = Vec::new();
+        let mut values: Vec<Decimal> = Vec::new();
+        for row in rows.iter() {
+            if let (Some(in_asset), Some(in_amount)) = (&row.in_asset, row.in_amount) {
+                let base_price = match in_asset == self.settings.base_asset {
+                    true => Decimal::ONE,
+                    false => self.price_information.get(
+                        in_asset,
+                        self.settings.base_asset,
+                        record.datetime)?,
+                };
+                legs.push((in_asset.clone(), in_amount - fees.take(in_asset)));
+                values.push(self.round(in_amount * base_price));
+            }
+        }
+        let total_value: Decimal = values.iter().sum();
+
+        // fees in the LP token are returned along with it; other fees add to the costs of the received assets
+        let fee_costs = self.withdraw_fees(record, fees)?;
+
+        let total_costs = fee_costs + match self.settings.liquidity {
+            LiquidityTreatment::Taxable => {
+                self.process_sale(&record.account, &lp_asset, Sale {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: lp_amount,
+                    proceeds: total_value,
+                    lots: record.lots.clone(),
+                    disposal: None,
+                })?;
+                total_value
+            },
+            LiquidityTreatment::NonTaxable => {
+                self.process_withdrawal(&record.account, &lp_asset, Withdrawal {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: lp_amount,
+                    lots: record.lots.clone(),
+                    disposal: None,
+                })?
+            },
+        };
+
+        // record the received assets, with the costs split by market value
+        let leg_costs = allocate(total_costs, &values, |share| self.round(share));
+        for ((asset, amount), costs) in legs.into_iter().zip(leg_costs) {
+            if asset == self.settings.base_asset {
+                continue;
+            }
+            self.process_purchase(&record.account, &asset, Purchase {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount,
+                base_price: unit_price(costs, amount, "in_amount")?,
+                actual_costs: costs,
+                income: None,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn withdraw_fees(&mut self, record: &TransactionRecord, fees: Fees) -> Result<Decimal, CryptoTaxError> {
+        // the remaining fees of a multi-leg transaction add to its costs: fees in the base asset
+        // at their amount, fees in foreign assets are withdrawn gain-neutrally, at their costs
+        let mut costs = fees.base;
+        for (fee_asset, fee_amount) in fees.foreign {
+            costs += self.process_withdrawal(&record.account, &fee_asset, Withdrawal {
+                tx_id: record.tx_id,
+                datetime: record.datetime,
+                amount: fee_amount,
+                lots: Vec::new(),
+                disposal: None,
+            })?;
+        }
+        Ok(costs)
+    }
+
+    fn process_fee(
+        &mut self,
+        record: &TransactionRecord,
+        asset: &str
+    ) -> Result<Decimal, CryptoTaxError> {
+        // fees paid in the given asset are returned, so that the caller can add them to the moved amount;
+        // fees paid in another foreign asset are withdrawn gain-neutrally,
+        // fees paid in the base asset are covered by the base balance
+        match (&record.fee_asset, record.fee_amount) {
+            (Some(fee_asset), Some(fee_amount)) if fee_asset == asset => Ok(fee_amount),
+            (Some(fee_asset), Some(fee_amount)) if fee_asset != self.settings.base_asset => {
+                self.process_withdrawal(&record.account, fee_asset, Withdrawal {
+                    tx_id: record.tx_id,
+                    datetime: record.datetime,
+                    amount: fee_amount,
+                    lots: Vec::new(),
+                    disposal: None,
+                })?;
+                Ok(Decimal::ZERO)
+            },
+            _ => Ok(Decimal::ZERO),
+        }
+    }
+
+    pub fn process_purchase(&mut self, account: &str, asset: &str, purchase: Purchase) -> Result<(), CryptoTaxError> {
+        // the received amount is net of fees, which must not use it up
+        if purchase.amount <= Decimal::ZERO {
+            return Err(CryptoTaxError::InvalidAmount("in_amount"));
+        }
+
+        let inventory = self.inventory(account, asset);
 
         // deposit asset
-        inventory.deposit(InventoryChange {
+        let inflow = InventoryChange {
             tx_id: purchase.tx_id,
             datetime: purchase.datetime,
             amount: purchase.amount,
             base_price: purchase.base_price,
-        });
+        };
+        inventory.deposit(inflow);
+        if let Some(uk_pooling) = &mut self.uk_pooling {
+            uk_pooling.acquire(asset, inflow);
+        }
 
-        // calculate possible gains from discounts / gifts / staking rewards etc.
-        let gains_raw = purchase.amount * purchase.base_price - purchase.actual_costs;
-        let gains : Option<f64> = match gains_raw >= self.currency_precision {
-            true => Some(gains_raw),
-            false => None,
+        // calculate possible gains from discounts / gifts / staking rewards etc.;
+        // the value of income, e.g. of staking rewards, is reported separately from gains
+        let gains_raw = self.round(purchase.amount * purchase.base_price - purchase.actual_costs);
+        let (gains, income_value) : (Option<Decimal>, Option<Decimal>) = match purchase.income {
+            Some(income) => (None, Some(self.round(income.value))),
+            None if gains_raw > Decimal::ZERO => (Some(gains_raw), None),
+            None => (None, None),
         };
 
         // create log entry
         self.log.push(CashflowRecord {
+            account: account.to_string(),
             asset: asset.to_string(),
             tx_out: None,
             datetime_out: None,
-            tx_in: purchase.tx_id,
-            datetime_in: purchase.datetime,
+            tx_in: Some(purchase.tx_id),
+            datetime_in: Some(purchase.datetime),
             amount: purchase.amount,
             base_price: purchase.base_price,
-            actual_costs: purchase.actual_costs,
+            actual_costs: self.round(purchase.actual_costs),
             actual_proceeds: None,
             gains_short_term: gains,
             gains_long_term: None,
+            holding_period: None,
+            matching_rule: None,
+            income: purchase.income.map(|income| income.kind),
+            income_value,
+            derivative_income: None,
+            disposal: None,
+            reason: None,
+            description: None,
+            expense: None,
         });
-    }
 
-    pub fn process_sale(&mut self, asset: &str, sale: Sale) {
-        let inventory = self.assets
-            .entry(asset.to_string())
-            .or_insert(Inventory::new(
-                self.accounting_method.clone(),
-                self.currency_precision));
+        Ok(())
+    }
 
+    pub fn process_sale(&mut self, account: &str, asset: &str, sale: Sale) -> Result<(), CryptoTaxError> {
         // withdraw asset from inventory
-        let outflows = inventory.withdraw(sale.amount);
+        let outflows = self.withdraw(account, asset, sale.amount, &sale.lots)?;
+        self.realize_sale(account, asset, sale, outflows)
+    }
 
-        // loop through outflows
-        for outflow in outflows {
-            let costs = outflow.base_price * outflow.amount;
+    fn realize_sale(
+        &mut self,
+        account: &str,
+        asset: &str,
+        sale: Sale,
+        outflows: Vec<InventoryChange>,
+    ) -> Result<(), CryptoTaxError> {
+        // with UK share pooling, the disposal is matched against the acquisitions later on
+        if let Some(uk_pooling) = &mut self.uk_pooling {
+            uk_pooling.dispose(account, asset, sale);
+            return Ok(());
+        }
 
-            // calculate proceeds proportional to the amount that we are taking from the layer;
-            // if we are taking close to zero, put all proceeds on this layer
-            let proceeds = match sale.amount >= self.currency_precision {
-                true => sale.proceeds / sale.amount * outflow.amount,
-                false => sale.proceeds,
-            };
+        // split the proceeds proportionally to the amounts that we are taking from the layers
+        let amounts: Vec<Decimal> = outflows.iter().map(|outflow| outflow.amount).collect();
+        let shares = allocate(sale.proceeds, &amounts, |share| self.round(share));
+
+        // loop through outflows
+        for (outflow, proceeds) in outflows.into_iter().zip(shares) {
+            let costs = self.round(outflow.base_price * outflow.amount);
 
             // calculate gains
             let gains = proceeds - costs;
 
-            // calculate holding duration
-            let duration = sale.datetime.signed_duration_since(outflow.datetime).num_days();
-            let is_longterm = duration > 365;
+            // determine the holding period, according to the rules of the tax jurisdiction
+            let holding_period = self.settings.jurisdiction.holding_period(outflow.datetime, sale.datetime);
+            let is_longterm = holding_period.is_some();
 
             // submit log entry
             self.log.push(CashflowRecord {
+                account: account.to_string(),
                 asset: asset.to_string(),
                 tx_out: Some(sale.tx_id),
                 datetime_out: Some(sale.datetime),
-                tx_in: outflow.tx_id,
-                datetime_in: outflow.datetime,
+                tx_in: Some(outflow.tx_id),
+                datetime_in: Some(outflow.datetime),
                 amount: -outflow.amount,
                 base_price: outflow.base_price,
                 actual_costs: costs,
                 actual_proceeds: Some(proceeds),
                 gains_short_term: if is_longterm {None} else {Some(gains)},
                 gains_long_term: if is_longterm {Some(gains)} else {None},
+                holding_period: Some(holding_period
+                    .unwrap_or(self.settings.jurisdiction.short_term())
+                    .to_string()),
+                matching_rule: None,
+                income: None,
+                income_value: None,
+                derivative_income: None,
+                disposal: sale.disposal.as_ref().map(|disposal| disposal.kind),
+                reason: sale.disposal.as_ref().and_then(|disposal| disposal.reason.clone()),
+                description: sale.disposal.as_ref().and_then(|disposal| disposal.description.clone()),
+                expense: None,
             });
         }
+
+        Ok(())
     }
 
-    fn process_withdrawal(&mut self, asset: &str, withdrawal: Withdrawal) -> f64 {
+    fn process_withdrawal(&mut self, account: &str, asset: &str, withdrawal: Withdrawal) -> Result<Decimal, CryptoTaxError> {
         // gain-neutral withdrawal of assets from inventory;
         // primarily used to withdraw assets to pay for fees
 
-        // find the correct inventory
-        let inventory = self.assets
-            .entry(asset.to_string())
-            .or_insert(Inventory::new(
-                self.accounting_method.clone(),
-                self.currency_precision));
-
         // withdraw asset from inventory
-        let outflows = inventory.withdraw(withdrawal.amount);
+        let outflows = self.withdraw(account, asset, withdrawal.amount, &withdrawal.lots)?;
+        if let Some(uk_pooling) = &mut self.uk_pooling {
+            uk_pooling.withdraw(asset, withdrawal.amount);
+        }
 
         // loop through outflows; generate logs and calculate total costs
-        let mut total_costs = 0f64;
+        let mut total_costs = Decimal::ZERO;
         for outflow in outflows {
-            let costs = outflow.amount * outflow.base_price;
+            let costs = self.round(outflow.amount * outflow.base_price);
             total_costs += costs;
 
             // submit log entry
             self.log.push(CashflowRecord {
+                account: account.to_string(),
                 asset: asset.to_string(),
                 tx_out: Some(withdrawal.tx_id),
                 datetime_out: Some(withdrawal.datetime),
-                tx_in: outflow.tx_id,
-                datetime_in: outflow.datetime,
+                tx_in: Some(outflow.tx_id),
+                datetime_in: Some(outflow.datetime),
                 amount: -outflow.amount,
                 base_price: outflow.base_price,
                 actual_costs: costs,
                 actual_proceeds: Some(costs),
                 gains_short_term: None,
                 gains_long_term: None,
+                holding_period: None,
+                matching_rule: None,
+                income: None,
+                income_value: None,
+                derivative_income: None,
+                disposal: withdrawal.disposal.as_ref().map(|disposal| disposal.kind),
+                reason: withdrawal.disposal.as_ref().and_then(|disposal| disposal.reason.clone()),
+                description: withdrawal.disposal.as_ref().and_then(|disposal| disposal.description.clone()),
+                expense: match withdrawal.disposal.as_ref().is_some_and(|disposal| disposal.expense) {
+                    true => Some(costs),
+                    false => None,
+                },
             });
         }
 
-        total_costs
+        Ok(total_costs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use std::error;
+    use crate::prices::PriceSource;
+
+    /// A price source that quotes the same price for every asset and time
+    struct FixedPrice(Decimal);
+
+    impl PriceSource for FixedPrice {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn get(&mut self, _: &str, _: &str, _: DateTime<Utc>) -> Result<Decimal, Box<dyn error::Error>> {
+            Ok(self.0)
+        }
+    }
+
+    fn settings(lending: LendingTreatment) -> Settings<'static> {
+        Settings {
+            accounting_method: AccountingMethod::FIFO,
+            base_asset: "EUR",
+            currency_precision: Decimal::new(1, 2),
+            check_base_balance: false,
+            inventory_scope: InventoryScope::Global,
+            jurisdiction: TaxJurisdiction::default(),
+            receipts: Receipts::default(),
+            disposals: Disposals::default(),
+            fees: FeeTreatment::Neutral,
+            liquidity: LiquidityTreatment::Taxable,
+            lending,
+        }
+    }
+
+    /// Buys 10 BTC for 100 EUR, lends 6 BTC, and loses 8 BTC in a liquidation, at a market price of 20 EUR
+    fn lend_and_liquidate(calculation: &mut CapitalGainsCalculation) {
+        let rows = "#Tx,Datetime (UTC),Account,Type,Disposed Asset,Disposed Amount,Received Asset,Received Amount,Fee Asset,Fee Amount\n\
+            1,2021-03-01T12:00:00Z,Lender,Trade,EUR,100,BTC,10,,\n\
+            2,2021-03-02T12:00:00Z,Lender,Lend,BTC,6,,,,\n\
+            3,2021-03-03T12:00:00Z,Lender,Liquidation,BTC,8,,,,\n";
+        let mut reader = csv::Reader::from_reader(rows.as_bytes());
+        for row in reader.deserialize::<TransactionRecord>() {
+            calculation.process_record(row.unwrap()).unwrap();
+        }
+    }
+
+    /// Amounts and proceeds of the disposals in the log
+    fn disposals(log: &[CashflowRecord]) -> Vec<(u32, Decimal, Option<Decimal>)> {
+        log.iter()
+            .filter_map(|entry| entry.tx_out.map(|tx_out| (tx_out, entry.amount, entry.actual_proceeds)))
+            .collect()
+    }
+
+    #[test]
+    fn liquidation_takes_collateral_lent_without_disposal_from_lent_assets() {
+        let settings = settings(LendingTreatment::NonDisposal);
+        let mut prices = PriceInformation::in_memory(vec![Box::new(FixedPrice(Decimal::from(20)))]).unwrap();
+        let mut calculation = CapitalGainsCalculation::new(&mut prices, &settings);
+        lend_and_liquidate(&mut calculation);
+
+        // the lent 6 BTC and 2 BTC of the spot inventory are disposed of by the liquidation
+        assert_eq!(disposals(&calculation.log), vec![
+            (3, Decimal::from(-6), Some(Decimal::from(120))),
+            (3, Decimal::from(-2), Some(Decimal::from(40))),
+        ]);
+        assert_eq!(calculation.lent_inventory("Lender", "BTC").balance(), Decimal::ZERO);
+        assert_eq!(calculation.inventory("Lender", "BTC").balance(), Decimal::from(2));
+    }
+
+    #[test]
+    fn liquidation_does_not_dispose_of_collateral_lent_as_disposal_again() {
+        let settings = settings(LendingTreatment::Disposal);
+        let mut prices = PriceInformation::in_memory(vec![Box::new(FixedPrice(Decimal::from(20)))]).unwrap();
+        let mut calculation = CapitalGainsCalculation::new(&mut prices, &settings);
+        lend_and_liquidate(&mut calculation);
+
+        // the lent 6 BTC are disposed of by the lending; the liquidation only takes the other 2 BTC from spot
+        assert_eq!(disposals(&calculation.log), vec![
+            (2, Decimal::from(-6), Some(Decimal::from(120))),
+            (3, Decimal::from(-2), Some(Decimal::from(40))),
+        ]);
+        assert_eq!(calculation.inventory("Lender", "BTC").balance(), Decimal::from(2));
     }
 }
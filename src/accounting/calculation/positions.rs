@@ -0,0 +1,35 @@
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+/// Ledger of open derivative positions (futures, perpetuals, margin positions), by account and contract
+///
+/// Positions are kept apart from the spot inventories: opening or closing a position does not move
+/// any assets; only the settlements of a position (realized PnL, funding) do.
+#[derive(Default)]
+pub struct PositionLedger {
+    positions: HashMap<(String, String), Decimal>,
+}
+
+impl PositionLedger {
+    pub fn open(&mut self, account: &str, contract: &str, size: Decimal) {
+        *self.positions
+            .entry((account.to_string(), contract.to_string()))
+            .or_insert(Decimal::ZERO) += size;
+    }
+
+    /// Closes the given size of a position; fails with the missing size if the position is smaller
+    pub fn close(&mut self, account: &str, contract: &str, size: Decimal) -> Result<(), Decimal> {
+        let key = (account.to_string(), contract.to_string());
+        let open_size = self.positions.get(&key).copied().unwrap_or(Decimal::ZERO);
+        if size > open_size {
+            return Err(size - open_size);
+        }
+
+        if size == open_size {
+            self.positions.remove(&key);
+        } else {
+            self.positions.insert(key, open_size - size);
+        }
+        Ok(())
+    }
+}
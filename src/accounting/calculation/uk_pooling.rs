@@ -0,0 +1,417 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::accounting::{CashflowRecord, InventoryChange, MatchingRule, Sale};
+use crate::accounting::calculation::{allocate, round};
+
+/// An inflow or outflow of an asset, in the order in which it was processed
+enum PoolEvent {
+    Acquisition(usize),
+    Disposal(usize),
+    Withdrawal(Decimal),
+    /// costs split off to another asset, e.g. to the assets received from a hard fork
+    CostSplit(Decimal),
+}
+
+struct Acquisition {
+    layer: InventoryChange,
+    remaining: Decimal,
+}
+
+struct Disposal {
+    account: String,
+    sale: Sale,
+    remaining: Decimal,
+    matches: Vec<Match>,
+}
+
+struct Match {
+    rule: MatchingRule,
+    tx_in: Option<u32>,
+    datetime_in: Option<DateTime<Utc>>,
+    amount: Decimal,
+    base_price: Decimal,
+}
+
+#[derive(Default)]
+struct AssetPool {
+    events: Vec<PoolEvent>,
+    acquisitions: Vec<Acquisition>,
+    disposals: Vec<Disposal>,
+}
+
+/// Share matching according to the HMRC rules for UK capital gains:
+///  1) acquisitions on the same day as the disposal
+///  2) acquisitions within the 30 days after the disposal ("bed and breakfast")
+///  3) the Section 104 pool of all other acquisitions, at average cost
+///
+/// The second rule looks ahead in the transaction stream, so disposals are collected
+/// while the transactions are processed, and matched once all transactions are known.
+#[derive(Default)]
+pub struct UkPooling {
+    assets: BTreeMap<String, AssetPool>,
+}
+
+impl UkPooling {
+    pub fn acquire(&mut self, asset: &str, layer: InventoryChange) {
+        let pool = self.assets.entry(asset.to_string()).or_default();
+        pool.events.push(PoolEvent::Acquisition(pool.acquisitions.len()));
+        pool.acquisitions.push(Acquisition {
+            remaining: layer.amount,
+            layer,
+        });
+    }
+
+    pub fn dispose(&mut self, account: &str, asset: &str, sale: Sale) {
+        let pool = self.assets.entry(asset.to_string()).or_default();
+        pool.events.push(PoolEvent::Disposal(pool.disposals.len()));
+        pool.disposals.push(Disposal {
+            account: account.to_string(),
+            remaining: sale.amount,
+            sale,
+            matches: Vec::new(),
+        });
+    }
+
+    /// Gain-neutral withdrawal (e.g. fees); reduces the Section 104 pool at average cost
+    pub fn withdraw(&mut self, asset: &str, amount: Decimal) {
+        let pool = self.assets.entry(asset.to_string()).or_default();
+        pool.events.push(PoolEvent::Withdrawal(amount));
+    }
+
+    /// Costs split off the asset, e.g. to a forked asset; reduces the costs of the Section 104 pool
+    pub fn split(&mut self, asset: &str, costs: Decimal) {
+        let pool = self.assets.entry(asset.to_string()).or_default();
+        pool.events.push(PoolEvent::CostSplit(costs));
+    }
+
+    /// Matches all collected disposals and returns their log entries
+    pub fn finish(self, currency_precision: Decimal) -> Vec<CashflowRecord> {
+        let mut log = Vec::new();
+        for (asset, mut pool) in self.assets {
+            pool.match_same_day();
+            pool.match_bed_and_breakfast();
+            pool.match_section_104();
+            pool.write_log(&asset, currency_precision, &mut log);
+        }
+        log
+    }
+}
+
+impl AssetPool {
+    fn match_same_day(&mut self) {
+        for day in self.disposal_days() {
+            let disposals = self.disposals_on(day);
+            let acquisitions = self.acquisitions_on(day);
+            self.match_days(&disposals, &acquisitions, MatchingRule::SameDay);
+        }
+    }
+
+    fn match_bed_and_breakfast(&mut self) {
+        let acquisition_days: BTreeSet<NaiveDate> = self.acquisitions
+            .iter()
+            .map(|acquisition| acquisition.layer.datetime.date_naive())
+            .collect();
+
+        // the disposals of a day are matched with the acquisitions of the following 30 days, earliest first
+        for day in self.disposal_days() {
+            let disposals = self.disposals_on(day);
+            for acquisition_day in acquisition_days.range(day + Duration::days(1)..=day + Duration::days(30)) {
+                let acquisitions = self.acquisitions_on(*acquisition_day);
+                self.match_days(&disposals, &acquisitions, MatchingRule::BedAndBreakfast);
+            }
+        }
+    }
+
+    fn disposal_days(&self) -> BTreeSet<NaiveDate> {
+        self.disposals
+            .iter()
+            .map(|disposal| disposal.sale.datetime.date_naive())
+            .collect()
+    }
+
+    fn disposals_on(&self, day: NaiveDate) -> Vec<usize> {
+        (0..self.disposals.len())
+            .filter(|index| self.disposals[*index].sale.datetime.date_naive() == day)
+            .collect()
+    }
+
+    fn acquisitions_on(&self, day: NaiveDate) -> Vec<usize> {
+        (0..self.acquisitions.len())
+            .filter(|index| self.acquisitions[*index].layer.datetime.date_naive() == day)
+            .collect()
+    }
+
+    fn match_days(&mut self, disposals: &[usize], acquisitions: &[usize], rule: MatchingRule) {
+        // all disposals of a day count as a single disposal, and all acquisitions of a day as a single
+        // acquisition at their average cost (TCGA92 s105); the matched amount is taken from each of them
+        // in proportion to its remaining amount
+        let disposed: Vec<Decimal> = disposals.iter().map(|index| self.disposals[*index].remaining).collect();
+        let acquired: Vec<Decimal> = acquisitions.iter().map(|index| self.acquisitions[*index].remaining).collect();
+        let total_acquired: Decimal = acquired.iter().sum();
+        let amount = disposed.iter().sum::<Decimal>().min(total_acquired);
+        if amount.is_zero() {
+            return;
+        }
+
+        let costs: Decimal = acquisitions
+            .iter()
+            .map(|index| self.acquisitions[*index].remaining * self.acquisitions[*index].layer.base_price)
+            .sum();
+        let base_price = (costs / total_acquired).normalize();
+
+        let mut acquisition_shares = allocate(amount, &acquired, |share| share);
+        let disposal_shares = allocate(amount, &disposed, |share| share);
+        for (disposal_index, mut disposal_share) in disposals.iter().zip(disposal_shares) {
+            for (acquisition_index, acquisition_share) in acquisitions.iter().zip(acquisition_shares.iter_mut()) {
+                let matched = disposal_share.min(*acquisition_share);
+                if matched.is_zero() {
+                    continue;
+                }
+                disposal_share -= matched;
+                *acquisition_share -= matched;
+
+                let acquisition = &mut self.acquisitions[*acquisition_index];
+                acquisition.remaining -= matched;
+                let disposal = &mut self.disposals[*disposal_index];
+                disposal.remaining -= matched;
+                disposal.matches.push(Match {
+                    rule,
+                    tx_in: Some(acquisition.layer.tx_id),
+                    datetime_in: Some(acquisition.layer.datetime),
+                    amount: matched,
+                    base_price,
+                });
+            }
+        }
+    }
+
+    fn match_section_104(&mut self) {
+        let mut pool_amount = Decimal::ZERO;
+        let mut pool_costs = Decimal::ZERO;
+
+        for event in self.events.iter() {
+            // the acquisitions that are not matched otherwise enter the pool when they happen;
+            // disposals and withdrawals leave it at the average cost at that time
+            let amount = match event {
+                PoolEvent::Acquisition(index) => {
+                    let acquisition = &self.acquisitions[*index];
+                    pool_amount += acquisition.remaining;
+                    pool_costs += acquisition.remaining * acquisition.layer.base_price;
+                    continue;
+                },
+                PoolEvent::CostSplit(costs) => {
+                    pool_costs -= (*costs).min(pool_costs);
+                    continue;
+                },
+                PoolEvent::Disposal(index) => self.disposals[*index].remaining,
+                PoolEvent::Withdrawal(amount) => *amount,
+            }.min(pool_amount);
+
+            if amount.is_zero() {
+                continue;
+            }
+
+            let base_price = (pool_costs / pool_amount).normalize();
+            pool_costs -= pool_costs / pool_amount * amount;
+            pool_amount -= amount;
+
+            if let PoolEvent::Disposal(index) = event {
+                let disposal = &mut self.disposals[*index];
+                disposal.remaining -= amount;
+                disposal.matches.push(Match {
+                    rule: MatchingRule::Section104,
+                    tx_in: None,
+                    datetime_in: None,
+                    amount,
+                    base_price,
+                });
+            }
+        }
+    }
+
+    fn write_log(&self, asset: &str, currency_precision: Decimal, log: &mut Vec<CashflowRecord>) {
+        for disposal in self.disposals.iter() {
+            let sale = &disposal.sale;
+
+            // split the proceeds proportionally to the matched amounts
+            let amounts: Vec<Decimal> = disposal.matches.iter().map(|matched| matched.amount).collect();
+            let shares = allocate(sale.proceeds, &amounts, |share| round(share, currency_precision));
+
+            for (matched, proceeds) in disposal.matches.iter().zip(shares) {
+                let costs = round(matched.base_price * matched.amount, currency_precision);
+
+                log.push(CashflowRecord {
+                    account: disposal.account.clone(),
+                    asset: asset.to_string(),
+                    tx_out: Some(sale.tx_id),
+                    datetime_out: Some(sale.datetime),
+                    tx_in: matched.tx_in,
+                    datetime_in: matched.datetime_in,
+                    amount: -matched.amount,
+                    base_price: matched.base_price,
+                    actual_costs: costs,
+                    actual_proceeds: Some(proceeds),
+                    gains_short_term: Some(proceeds - costs),
+                    gains_long_term: None,
+                    holding_period: None,
+                    matching_rule: Some(matched.rule),
+                    income: None,
+                    income_value: None,
+                    derivative_income: None,
+                    disposal: sale.disposal.as_ref().map(|disposal| disposal.kind),
+                    reason: sale.disposal.as_ref().and_then(|disposal| disposal.reason.clone()),
+                    description: sale.disposal.as_ref().and_then(|disposal| disposal.description.clone()),
+                    expense: None,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn day(day: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap() + Duration::days(day)
+    }
+
+    fn acquire(pooling: &mut UkPooling, tx_id: u32, on: i64, amount: i64, base_price: i64) {
+        pooling.acquire("BTC", InventoryChange {
+            tx_id,
+            datetime: day(on),
+            amount: Decimal::from(amount),
+            base_price: Decimal::from(base_price),
+        });
+    }
+
+    fn dispose(pooling: &mut UkPooling, tx_id: u32, on: i64, amount: i64, proceeds: i64) {
+        pooling.dispose("Exchange", "BTC", Sale {
+            tx_id,
+            datetime: day(on),
+            amount: Decimal::from(amount),
+            proceeds: Decimal::from(proceeds),
+            lots: Vec::new(),
+            disposal: None,
+        });
+    }
+
+    /// (tx_in, matching rule, amount, costs) of each log entry
+    fn matches(pooling: UkPooling) -> Vec<(Option<u32>, MatchingRule, Decimal, Decimal)> {
+        pooling
+            .finish(Decimal::new(1, 2))
+            .into_iter()
+            .map(|entry| (entry.tx_in, entry.matching_rule.unwrap(), -entry.amount, entry.actual_costs))
+            .collect()
+    }
+
+    #[test]
+    fn matches_acquisitions_on_the_same_day_first() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 10, 100);
+        dispose(&mut pooling, 2, 50, 5, 1000);
+        acquire(&mut pooling, 3, 50, 2, 200);
+        acquire(&mut pooling, 4, 55, 10, 300);
+
+        let matches = matches(pooling);
+        assert_eq!(matches.len(), 2);
+        assert!(matches!(matches[0], (Some(3), MatchingRule::SameDay, _, _)));
+        assert_eq!((matches[0].2, matches[0].3), (Decimal::from(2), Decimal::from(400)));
+        assert!(matches!(matches[1], (Some(4), MatchingRule::BedAndBreakfast, _, _)));
+        assert_eq!((matches[1].2, matches[1].3), (Decimal::from(3), Decimal::from(900)));
+    }
+
+    #[test]
+    fn matches_the_acquisitions_of_a_day_at_their_average_cost() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 1, 100);
+        acquire(&mut pooling, 2, 0, 1, 300);
+        dispose(&mut pooling, 3, 0, 1, 250);
+
+        let log = pooling.finish(Decimal::new(1, 2));
+        let costs: Decimal = log.iter().map(|entry| entry.actual_costs).sum();
+        let gains: Decimal = log.iter().filter_map(|entry| entry.gains_short_term).sum();
+        assert_eq!((costs, gains), (Decimal::from(200), Decimal::from(50)));
+        assert!(log.iter().all(|entry| matches!(entry.matching_rule, Some(MatchingRule::SameDay))));
+    }
+
+    #[test]
+    fn matches_the_disposals_of_a_day_as_one_disposal() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 10, 100);
+        dispose(&mut pooling, 2, 5, 2, 400);
+        acquire(&mut pooling, 3, 5, 1, 400);
+        dispose(&mut pooling, 4, 5, 2, 400);
+
+        // the same-day acquisition is shared by both disposals, the rest comes from the pool
+        let matches = matches(pooling);
+        assert_eq!(matches.len(), 4);
+        assert!(matches!(matches[0], (Some(3), MatchingRule::SameDay, _, _)));
+        assert_eq!((matches[0].2, matches[0].3), (Decimal::new(5, 1), Decimal::from(200)));
+        assert!(matches!(matches[1], (None, MatchingRule::Section104, _, _)));
+        assert_eq!((matches[1].2, matches[1].3), (Decimal::new(15, 1), Decimal::from(150)));
+    }
+
+    #[test]
+    fn matches_acquisitions_within_30_days_after_the_disposal() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 10, 100);
+        dispose(&mut pooling, 2, 10, 6, 1200);
+        acquire(&mut pooling, 3, 40, 4, 150);
+        acquire(&mut pooling, 4, 41, 4, 500);
+
+        let matches = matches(pooling);
+        assert_eq!(matches.len(), 2);
+        assert!(matches!(matches[0], (Some(3), MatchingRule::BedAndBreakfast, _, _)));
+        assert_eq!((matches[0].2, matches[0].3), (Decimal::from(4), Decimal::from(600)));
+        // the acquisition 31 days after the disposal is not matched; the rest comes from the pool
+        assert!(matches!(matches[1], (None, MatchingRule::Section104, _, _)));
+        assert_eq!((matches[1].2, matches[1].3), (Decimal::from(2), Decimal::from(200)));
+    }
+
+    #[test]
+    fn matches_the_section_104_pool_at_its_running_average_cost() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 10, 100);
+        acquire(&mut pooling, 2, 1, 10, 200);
+        dispose(&mut pooling, 3, 2, 5, 1000);
+        acquire(&mut pooling, 4, 60, 10, 300);
+        dispose(&mut pooling, 5, 100, 10, 3000);
+
+        // 20 @ 150, minus 5; then 15 @ 150 plus 10 @ 300 = 25 @ 210
+        let matches = matches(pooling);
+        assert_eq!(matches.len(), 2);
+        assert!(matches!(matches[0], (None, MatchingRule::Section104, _, _)));
+        assert_eq!((matches[0].2, matches[0].3), (Decimal::from(5), Decimal::from(750)));
+        assert!(matches!(matches[1], (None, MatchingRule::Section104, _, _)));
+        assert_eq!((matches[1].2, matches[1].3), (Decimal::from(10), Decimal::from(2100)));
+    }
+
+    #[test]
+    fn split_costs_leave_the_pool() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 1, 10000);
+        pooling.split("BTC", Decimal::new(166667, 2));
+        dispose(&mut pooling, 2, 100, 1, 12000);
+
+        let matches = matches(pooling);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].3, Decimal::new(833333, 2));
+    }
+
+    #[test]
+    fn withdrawals_leave_the_pool_at_average_cost() {
+        let mut pooling = UkPooling::default();
+        acquire(&mut pooling, 1, 0, 10, 100);
+        acquire(&mut pooling, 2, 1, 10, 200);
+        pooling.withdraw("BTC", Decimal::from(10));
+        dispose(&mut pooling, 3, 2, 10, 2000);
+
+        let matches = matches(pooling);
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].2, matches[0].3), (Decimal::from(10), Decimal::from(1500)));
+    }
+}
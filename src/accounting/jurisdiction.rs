@@ -0,0 +1,156 @@
+use chrono::{DateTime, Months, Utc};
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+/// Holding-period rules of a tax jurisdiction
+///
+/// Gains are assigned to the bucket of the longest holding period that was exceeded;
+/// gains from assets that were held for a shorter time are assigned to `short_term`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "JurisdictionConfig")]
+pub struct TaxJurisdiction {
+    short_term: String,
+    holding_periods: Vec<HoldingPeriod>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HoldingPeriod {
+    /// An asset exceeds the period if it is disposed of after the same date, this many months later
+    months: u32,
+    bucket: String,
+}
+
+/// A jurisdiction is configured either by the name of a preset, or by its rules
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JurisdictionConfig {
+    Preset(String),
+    Rules {
+        #[serde(default = "default_short_term")]
+        short_term: String,
+        #[serde(default)]
+        holding_periods: Vec<HoldingPeriod>,
+    },
+}
+
+impl TryFrom<JurisdictionConfig> for TaxJurisdiction {
+    type Error = String;
+
+    fn try_from(config: JurisdictionConfig) -> Result<Self, Self::Error> {
+        match config {
+            JurisdictionConfig::Preset(name) => TaxJurisdiction::preset(&name)
+                .ok_or(format!("unknown tax jurisdiction \"{}\"", name)),
+            JurisdictionConfig::Rules { short_term, mut holding_periods } => {
+                holding_periods.sort_by_key(|period| period.months);
+                Ok(TaxJurisdiction { short_term, holding_periods })
+            },
+        }
+    }
+}
+
+impl Default for TaxJurisdiction {
+    fn default() -> Self {
+        TaxJurisdiction {
+            short_term: default_short_term(),
+            holding_periods: vec![HoldingPeriod::new(12, "long_term")],
+        }
+    }
+}
+
+impl HoldingPeriod {
+    fn new(months: u32, bucket: &str) -> HoldingPeriod {
+        HoldingPeriod {
+            months,
+            bucket: bucket.to_string(),
+        }
+    }
+}
+
+impl TaxJurisdiction {
+    /// Rules of a known jurisdiction, by its country code
+    pub fn preset(name: &str) -> Option<TaxJurisdiction> {
+        match name.to_uppercase().as_str() {
+            // §23 EStG: private sales are tax-free after more than one year
+            "DE" => Some(TaxJurisdiction {
+                short_term: "taxable".to_string(),
+                holding_periods: vec![HoldingPeriod::new(12, "tax_free")],
+            }),
+            // assets held for more than one year are taxed at the long-term rates
+            "US" => Some(TaxJurisdiction::default()),
+            // no distinction by holding period
+            "UK" | "GB" => Some(TaxJurisdiction {
+                short_term: "gains".to_string(),
+                holding_periods: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Name of the bucket of gains from assets held shorter than all holding periods
+    pub fn short_term(&self) -> &str {
+        &self.short_term
+    }
+
+    /// Name of the bucket of the longest holding period that an asset exceeded, if any
+    pub fn holding_period(&self, acquired: DateTime<Utc>, disposed: DateTime<Utc>) -> Option<&str> {
+        // holding periods are computed from calendar dates, so that leap years are respected;
+        // an asset acquired on Feb 29 completes a year on Feb 28 of the following year
+        let acquired = acquired.date_naive();
+        let disposed = disposed.date_naive();
+        self.holding_periods
+            .iter()
+            .rev()
+            .find(|period| acquired
+                .checked_add_months(Months::new(period.months))
+                .is_some_and(|end| disposed > end))
+            .map(|period| period.bucket.as_str())
+    }
+}
+
+fn default_short_term() -> String {
+    "short_term".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn exceeds_a_holding_period_only_after_the_same_date() {
+        let germany = TaxJurisdiction::preset("DE").unwrap();
+        let acquired = date(2021, 3, 18);
+        assert_eq!(germany.holding_period(acquired, date(2022, 3, 18)), None);
+        assert_eq!(germany.holding_period(acquired, date(2022, 3, 19)), Some("tax_free"));
+    }
+
+    #[test]
+    fn completes_a_year_from_february_29_on_february_28() {
+        let germany = TaxJurisdiction::preset("DE").unwrap();
+        let acquired = date(2020, 2, 29);
+        assert_eq!(germany.holding_period(acquired, date(2021, 2, 28)), None);
+        assert_eq!(germany.holding_period(acquired, date(2021, 3, 1)), Some("tax_free"));
+    }
+
+    #[test]
+    fn assigns_the_longest_holding_period_that_was_exceeded() {
+        let jurisdiction = TaxJurisdiction::try_from(JurisdictionConfig::Rules {
+            short_term: "short".to_string(),
+            holding_periods: vec![HoldingPeriod::new(24, "exempt"), HoldingPeriod::new(12, "reduced")],
+        }).unwrap();
+        let acquired = date(2021, 3, 18);
+        assert_eq!(jurisdiction.short_term(), "short");
+        assert_eq!(jurisdiction.holding_period(acquired, date(2022, 1, 1)), None);
+        assert_eq!(jurisdiction.holding_period(acquired, date(2022, 6, 1)), Some("reduced"));
+        assert_eq!(jurisdiction.holding_period(acquired, date(2023, 6, 1)), Some("exempt"));
+    }
+
+    #[test]
+    fn rejects_unknown_presets() {
+        assert!(TaxJurisdiction::try_from(JurisdictionConfig::Preset("XX".to_string())).is_err());
+    }
+}
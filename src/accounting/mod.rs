@@ -1,73 +1,370 @@
 pub mod accountant;
+pub mod jurisdiction;
 pub mod reports;
 mod calculation;
 
 use chrono::{DateTime, Utc};
-use serde::{Serialize,Deserialize};
+use rust_decimal::Decimal;
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct TransactionRecord {
+    #[serde(rename = "#Tx")]
     tx_id: u32,
+    #[serde(rename = "Datetime (UTC)")]
     datetime: DateTime<Utc>,
+    #[serde(rename = "Account")]
     account: String,
-    tx_type: String, // TODO: refactor to enum
+    #[serde(rename = "Type")]
+    kind: TransactionKind,
+    #[serde(rename = "Disposed Asset")]
     out_asset: Option<String>,
-    out_amount: Option<f64>,
-    in_asset: String,
-    in_amount: f64,
+    #[serde(rename = "Disposed Amount")]
+    out_amount: Option<Decimal>,
+    #[serde(rename = "Received Asset")]
+    in_asset: Option<String>,
+    #[serde(rename = "Received Amount")]
+    in_amount: Option<Decimal>,
+    #[serde(rename = "Fee Asset")]
     fee_asset: Option<String>,
-    fee_amount: Option<f64>,
+    #[serde(rename = "Fee Amount")]
+    fee_amount: Option<Decimal>,
+    #[serde(rename = "Destination Account", default)]
+    destination_account: Option<String>,
+    #[serde(rename = "Lots", default, deserialize_with = "deserialize_lots")]
+    lots: Vec<u32>,
+    #[serde(rename = "Reason", default)]
+    reason: Option<String>,
+    #[serde(rename = "Description", default)]
+    description: Option<String>,
+    #[serde(rename = "Contract", default)]
+    contract: Option<String>,
+    #[serde(rename = "Acquisition Datetime (UTC)", default)]
+    acquisition_datetime: Option<DateTime<Utc>>,
+    #[serde(rename = "Acquisition Costs", default)]
+    acquisition_costs: Option<Decimal>,
+    #[serde(rename = "Disposed Token ID", default)]
+    out_token_id: Option<String>,
+    #[serde(rename = "Received Token ID", default)]
+    in_token_id: Option<String>,
+}
+
+/// Separates the contract of a non-fungible token from its token id, e.g. "BAYC#1234"
+const TOKEN_SEPARATOR: char = '#';
+
+impl TransactionRecord {
+    /// Identifies non-fungible tokens by their contract and token id, so that each token has its own inventory
+    fn with_token_ids(mut self) -> TransactionRecord {
+        if let (Some(contract), Some(token_id)) = (&self.out_asset, self.out_token_id.take()) {
+            self.out_asset = Some(format!("{}{}{}", contract, TOKEN_SEPARATOR, token_id));
+        }
+        if let (Some(contract), Some(token_id)) = (&self.in_asset, self.in_token_id.take()) {
+            self.in_asset = Some(format!("{}{}{}", contract, TOKEN_SEPARATOR, token_id));
+        }
+        self
+    }
+}
+
+/// Whether the asset is a non-fungible token, which has no market price of its own
+fn is_token(asset: &str) -> bool {
+    asset.contains(TOKEN_SEPARATOR)
+}
+
+fn deserialize_lots<'de, D>(deserializer: D) -> Result<Vec<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // lots are given as a list of transaction ids, separated by semicolons, e.g. "3;7"
+    let lots = String::deserialize(deserializer)?;
+    lots.split(';')
+        .map(str::trim)
+        .filter(|lot| !lot.is_empty())
+        .map(|lot| lot.parse::<u32>().map_err(de::Error::custom))
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub enum TransactionKind {
+    Trade,
+    #[serde(rename = "Staking Reward", alias = "StakingReward")]
+    StakingReward,
+    #[serde(rename = "Lending Interest", alias = "LendingInterest")]
+    LendingInterest,
+    Airdrop,
+    Fork,
+    Mining,
+    Deposit,
+    Withdrawal,
+    Transfer,
+    Gift,
+    #[serde(rename = "Gift Received", alias = "GiftReceived")]
+    GiftReceived,
+    Donation,
+    Loss,
+    Spend,
+    Fee,
+    #[serde(rename = "Futures Open", alias = "FuturesOpen")]
+    FuturesOpen,
+    #[serde(rename = "Futures Close", alias = "FuturesClose")]
+    FuturesClose,
+    #[serde(rename = "Realized PnL", alias = "RealizedPnL")]
+    RealizedPnl,
+    Funding,
+    Lend,
+    #[serde(rename = "Lend Return", alias = "LendReturn")]
+    LendReturn,
+    #[serde(rename = "Loan Drawdown", alias = "LoanDrawdown")]
+    LoanDrawdown,
+    #[serde(rename = "Loan Repayment", alias = "LoanRepayment")]
+    LoanRepayment,
+    Liquidation,
+    Mint,
+    #[serde(rename = "Liquidity Add", alias = "LiquidityAdd")]
+    LiquidityAdd,
+    #[serde(rename = "Liquidity Remove", alias = "LiquidityRemove")]
+    LiquidityRemove,
+}
+
+impl TransactionKind {
+    /// Whether the transaction may consist of several rows that share their transaction id
+    pub fn is_multi_leg(&self) -> bool {
+        matches!(self,
+            TransactionKind::Trade | TransactionKind::LiquidityAdd | TransactionKind::LiquidityRemove)
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct CashflowRecord {
     tx_out: Option<u32>,
     datetime_out: Option<DateTime<Utc>>,
-    tx_in: u32,
-    datetime_in: DateTime<Utc>,
+    tx_in: Option<u32>,
+    datetime_in: Option<DateTime<Utc>>,
+    account: String,
     asset: String,
-    amount: f64,
-    base_price: f64,
-    actual_costs: f64,
-    actual_proceeds: Option<f64>,
-    gains_short_term: Option<f64>,
-    gains_long_term: Option<f64>,
+    amount: Decimal,
+    base_price: Decimal,
+    actual_costs: Decimal,
+    actual_proceeds: Option<Decimal>,
+    gains_short_term: Option<Decimal>,
+    gains_long_term: Option<Decimal>,
+    holding_period: Option<String>,
+    matching_rule: Option<MatchingRule>,
+    income: Option<IncomeKind>,
+    income_value: Option<Decimal>,
+    derivative_income: Option<Decimal>,
+    disposal: Option<DisposalKind>,
+    reason: Option<String>,
+    description: Option<String>,
+    expense: Option<Decimal>,
+}
+
+/// Classification of inflows that are taxed as income rather than as capital gains
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncomeKind {
+    Staking,
+    LendingInterest,
+    Airdrop,
+    Fork,
+    Mining,
+}
+
+/// Classification of disposals other than sales
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisposalKind {
+    Gift,
+    Donation,
+    Loss,
+    Spend,
+    Fee,
+    Liquidation,
+}
+
+/// Treatment of a kind of disposals
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisposalTreatment {
+    /// the assets leave the inventory at cost, without gains
+    AtCost,
+    /// the assets are disposed of at their market value, which may realize gains
+    AtMarket,
+    /// the assets are disposed of without proceeds, which realizes their costs as a loss
+    RealizedLoss,
+}
+
+/// Treatments of gifts, donations and losses
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Disposals {
+    pub gift: DisposalTreatment,
+    pub donation: DisposalTreatment,
+    pub loss: DisposalTreatment,
+}
+
+impl Default for Disposals {
+    fn default() -> Self {
+        Disposals {
+            gift: DisposalTreatment::AtCost,
+            donation: DisposalTreatment::AtMarket,
+            loss: DisposalTreatment::RealizedLoss,
+        }
+    }
+}
+
+/// Treatment of fees that are not part of another transaction, e.g. subscription fees
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeTreatment {
+    /// the assets leave the inventory at cost, without gains
+    Neutral,
+    /// the assets are disposed of at their market value, which may realize gains
+    Disposal,
+    /// the assets leave the inventory at cost, and their costs are a deductible expense
+    Expense,
 }
 
+/// Treatment of assets lent to a lending platform
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LendingTreatment {
+    /// lent assets keep their costs and acquisition dates, in a separate inventory of lent assets
+    NonDisposal,
+    /// lending is a disposal at market value, and the return an acquisition at market value
+    Disposal,
+}
+
+/// Treatment of deposits into and withdrawals from liquidity pools
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiquidityTreatment {
+    /// the swap of assets for LP tokens, and back, is a disposal at market value
+    Taxable,
+    /// LP tokens inherit the costs of the deposited assets, and pass them on when they are returned
+    NonTaxable,
+}
+
+/// Cost basis of assets that are received without a purchase, e.g. from an airdrop
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CostBasis {
+    /// the received assets have no costs
+    Zero,
+    /// the received assets cost their market value at the time of receipt
+    Market,
+    /// the costs of the parent asset (the disposed asset of the transaction) are split
+    /// by market value, and the received assets keep the acquisition dates of their parent lots
+    Split,
+}
+
+/// Treatment of a kind of received assets: their cost basis, and whether their market value is income
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct ReceiptTreatment {
+    pub cost_basis: CostBasis,
+    #[serde(default)]
+    pub income: bool,
+}
+
+/// Treatments of airdrops, hard forks and mining rewards
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Receipts {
+    pub airdrop: ReceiptTreatment,
+    pub fork: ReceiptTreatment,
+    pub mining: ReceiptTreatment,
+}
+
+impl Default for Receipts {
+    fn default() -> Self {
+        Receipts {
+            airdrop: ReceiptTreatment { cost_basis: CostBasis::Market, income: true },
+            fork: ReceiptTreatment { cost_basis: CostBasis::Zero, income: false },
+            mining: ReceiptTreatment { cost_basis: CostBasis::Market, income: true },
+        }
+    }
+}
+
+/// Rule by which a disposal was matched to its acquisitions, with UK share pooling
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchingRule {
+    SameDay,
+    BedAndBreakfast,
+    #[serde(rename = "section_104")]
+    Section104,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub enum AccountingMethod {
     FIFO,
     LIFO,
+    HIFO,
+    LOFO,
+    #[serde(alias = "SPECIFIC")]
+    SpecificIdentification,
+    #[serde(alias = "AVCO")]
+    AverageCost,
+    #[serde(alias = "UK")]
+    UkPooling,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InventoryScope {
+    Global,
+    PerAccount,
 }
 
 #[derive(Copy, Clone)]
 pub struct Purchase {
     tx_id: u32,
     datetime: DateTime<Utc>,
-    amount: f64,
-    base_price: f64,
-    actual_costs: f64,
+    amount: Decimal,
+    base_price: Decimal,
+    actual_costs: Decimal,
+    income: Option<Income>,
 }
 
 #[derive(Copy, Clone)]
+pub struct Income {
+    kind: IncomeKind,
+    value: Decimal,
+}
+
+#[derive(Clone)]
 pub struct Sale {
     tx_id: u32,
     datetime: DateTime<Utc>,
-    amount: f64,
-    proceeds: f64,
+    amount: Decimal,
+    proceeds: Decimal,
+    lots: Vec<u32>,
+    disposal: Option<Disposal>,
 }
 
 #[derive(Copy, Clone)]
 pub struct InventoryChange {
     tx_id: u32,
     datetime: DateTime<Utc>,
-    amount: f64,
-    base_price: f64,
+    amount: Decimal,
+    base_price: Decimal,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct Withdrawal {
     tx_id: u32,
     datetime: DateTime<Utc>,
-    amount: f64,
+    amount: Decimal,
+    lots: Vec<u32>,
+    disposal: Option<Disposal>,
+}
+
+/// Kind of a disposal other than a sale, and the reason and description given for it
+#[derive(Clone)]
+pub struct Disposal {
+    kind: DisposalKind,
+    reason: Option<String>,
+    description: Option<String>,
+    /// whether the costs of the disposed assets are a deductible expense
+    expense: bool,
 }
\ No newline at end of file
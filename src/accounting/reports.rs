@@ -1,6 +1,25 @@
+use chrono::Datelike;
 use csv::Writer;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
 
-use crate::accounting::CashflowRecord;
+use crate::accounting::{CashflowRecord, IncomeKind};
+use crate::error::CryptoTaxError;
+
+/// Writes the rows of a report to a CSV file, with a header row
+fn write_csv<T: Serialize>(path: &str, rows: &[T]) -> Result<(), CryptoTaxError> {
+    let output_error = |source: csv::Error| CryptoTaxError::Output {
+        path: path.to_string(),
+        source,
+    };
+    let mut writer = Writer::from_path(path).map_err(output_error)?;
+    for row in rows.iter() {
+        writer.serialize(row).map_err(output_error)?;
+    }
+    writer.flush().map_err(|err| output_error(err.into()))?;
+    Ok(())
+}
 
 pub struct CapitalGainsReport {
     records: Vec<CashflowRecord>,
@@ -13,10 +32,181 @@ impl CapitalGainsReport {
         }
     }
 
-    pub fn write_to_file(&self, path: &str) {
-        let mut writer = Writer::from_path(path.to_string()).unwrap();
-        for entry in self.records.iter() {
-            writer.serialize(entry).unwrap();
+    pub fn write_to_file(&self, path: &str) -> Result<(), CryptoTaxError> {
+        write_csv(path, &self.records)
+    }
+
+    pub fn records(&self) -> &[CashflowRecord] {
+        &self.records
+    }
+}
+
+/// Annual summary of private sales according to §23 EStG, with the figures needed for Anlage SO
+pub struct PrivateSalesReport {
+    years: Vec<PrivateSalesYear>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrivateSalesYear {
+    year: i32,
+    proceeds: Decimal,
+    costs: Decimal,
+    gains: Decimal,
+    losses: Decimal,
+    expenses: Decimal,
+    net_gains: Decimal,
+    exemption_limit: Decimal,
+    exemption_exceeded: bool,
+    taxable_gains: Decimal,
+}
+
+impl PrivateSalesReport {
+    /// Nets the short-term gains and losses of all disposals per calendar year
+    pub fn new(report: &CapitalGainsReport) -> PrivateSalesReport {
+        let mut years: BTreeMap<i32, PrivateSalesYear> = BTreeMap::new();
+
+        // only disposals within the holding period are private sales; gains from acquisitions
+        // (e.g. staking rewards) and gain-neutral withdrawals are not
+        for entry in report.records().iter() {
+            // deductible expenses, e.g. standalone fees, reduce the gains of the year (Werbungskosten)
+            if let (Some(datetime_out), Some(expense)) = (entry.datetime_out, entry.expense) {
+                let year = datetime_out.year();
+                years.entry(year).or_insert_with(|| PrivateSalesYear::new(year)).expenses += expense;
+            }
+
+            if let (Some(datetime_out), Some(proceeds), Some(gains)) =
+                (entry.datetime_out, entry.actual_proceeds, entry.gains_short_term)
+            {
+                let year = datetime_out.year();
+                let summary = years.entry(year).or_insert_with(|| PrivateSalesYear::new(year));
+                summary.proceeds += proceeds;
+                summary.costs += entry.actual_costs;
+                if gains > Decimal::ZERO {
+                    summary.gains += gains;
+                } else {
+                    summary.losses -= gains;
+                }
+            }
+        }
+
+        // the exemption limit is a Freigrenze: once exceeded, the full net gains are taxable
+        for summary in years.values_mut() {
+            summary.net_gains = summary.gains - summary.losses - summary.expenses;
+            summary.exemption_exceeded = summary.net_gains >= summary.exemption_limit;
+            summary.taxable_gains = match summary.exemption_exceeded || summary.net_gains < Decimal::ZERO {
+                true => summary.net_gains,
+                false => Decimal::ZERO,
+            };
+        }
+
+        PrivateSalesReport {
+            years: years.into_values().collect(),
+        }
+    }
+
+    pub fn print(&self) {
+        for summary in self.years.iter() {
+            println!(
+                "{}: net gains {} (gains {}, losses {}, expenses {}), exemption limit {} {}, taxable gains {}",
+                summary.year,
+                summary.net_gains,
+                summary.gains,
+                summary.losses,
+                summary.expenses,
+                summary.exemption_limit,
+                if summary.exemption_exceeded {"exceeded"} else {"not exceeded"},
+                summary.taxable_gains);
+        }
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<(), CryptoTaxError> {
+        write_csv(path, &self.years)
+    }
+}
+
+impl PrivateSalesYear {
+    fn new(year: i32) -> PrivateSalesYear {
+        PrivateSalesYear {
+            year,
+            proceeds: Decimal::ZERO,
+            costs: Decimal::ZERO,
+            gains: Decimal::ZERO,
+            losses: Decimal::ZERO,
+            expenses: Decimal::ZERO,
+            net_gains: Decimal::ZERO,
+            exemption_limit: exemption_limit(year),
+            exemption_exceeded: false,
+            taxable_gains: Decimal::ZERO,
+        }
+    }
+}
+
+/// Freigrenze for private sales: 600 EUR up to 2023, 1000 EUR from 2024 (Wachstumschancengesetz)
+fn exemption_limit(year: i32) -> Decimal {
+    match year >= 2024 {
+        true => Decimal::new(1000, 0),
+        false => Decimal::new(600, 0),
+    }
+}
+
+/// Annual totals of income (e.g. staking rewards), by kind of income
+pub struct IncomeReport {
+    years: Vec<IncomeYear>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct IncomeYear {
+    year: i32,
+    staking: Decimal,
+    lending_interest: Decimal,
+    airdrop: Decimal,
+    fork: Decimal,
+    mining: Decimal,
+    total: Decimal,
+    derivatives: Decimal,
+}
+
+impl IncomeReport {
+    pub fn new(report: &CapitalGainsReport) -> IncomeReport {
+        let mut years: BTreeMap<i32, IncomeYear> = BTreeMap::new();
+
+        for entry in report.records().iter() {
+            if let (Some(income), Some(value), Some(datetime_in)) =
+                (entry.income, entry.income_value, entry.datetime_in)
+            {
+                let year = datetime_in.year();
+                let summary = years.entry(year).or_insert_with(|| IncomeYear {
+                    year,
+                    ..Default::default()
+                });
+                match income {
+                    IncomeKind::Staking => summary.staking += value,
+                    IncomeKind::LendingInterest => summary.lending_interest += value,
+                    IncomeKind::Airdrop => summary.airdrop += value,
+                    IncomeKind::Fork => summary.fork += value,
+                    IncomeKind::Mining => summary.mining += value,
+                };
+                summary.total += value;
+            }
+
+            // derivative income is listed separately, since it is no income from receipts
+            if let (Some(value), Some(datetime)) =
+                (entry.derivative_income, entry.datetime_out.or(entry.datetime_in))
+            {
+                let year = datetime.year();
+                years.entry(year).or_insert_with(|| IncomeYear {
+                    year,
+                    ..Default::default()
+                }).derivatives += value;
+            }
+        }
+
+        IncomeReport {
+            years: years.into_values().collect(),
         }
     }
+
+    pub fn write_to_file(&self, path: &str) -> Result<(), CryptoTaxError> {
+        write_csv(path, &self.years)
+    }
 }
\ No newline at end of file
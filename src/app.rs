@@ -3,6 +3,8 @@ use clap::{Arg};
 pub struct App {
     input_path: String,
     output_path: String,
+    summary_path: Option<String>,
+    income_path: Option<String>,
     conf_path: String,
     clear_cache: bool,
 }
@@ -23,6 +25,15 @@ impl App {
                 .long("output")
                 .takes_value(true)
                 .help("Capital Gains Statement to write"))
+            .arg(Arg::with_name("summary_path")
+                .short("s")
+                .long("summary")
+                .takes_value(true)
+                .help("Annual summary of private sales (§23 EStG) to write"))
+            .arg(Arg::with_name("income_path")
+                .long("income")
+                .takes_value(true)
+                .help("Annual income statement (staking, lending, airdrops, forks, mining) to write"))
             .arg(Arg::with_name("config_path")
                 .short("c")
                 .long("config")
@@ -41,6 +52,10 @@ impl App {
             output_path: matches.value_of("output_path")
                 .unwrap_or("cashflows.csv")
                 .to_string(),
+            summary_path: matches.value_of("summary_path")
+                .map(str::to_string),
+            income_path: matches.value_of("income_path")
+                .map(str::to_string),
             conf_path: matches.value_of("config_path")
                 .unwrap_or("config.yaml")
                 .to_string(),
@@ -51,5 +66,7 @@ impl App {
     pub fn get_config_path(&self) -> &str { &self.conf_path }
     pub fn get_input_path(&self) -> &str { &self.input_path }
     pub fn get_output_path(&self) -> &str { &self.output_path }
+    pub fn get_summary_path(&self) -> Option<&str> { self.summary_path.as_deref() }
+    pub fn get_income_path(&self) -> Option<&str> { self.income_path.as_deref() }
     pub fn get_clear_cache(&self) -> bool { self.clear_cache }
 }
\ No newline at end of file
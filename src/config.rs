@@ -1,14 +1,37 @@
 use std::collections::HashMap;
 use std::error;
+use rust_decimal::Decimal;
 use serde::{Deserialize};
 
-use crate::accounting::AccountingMethod;
+use crate::accounting::{AccountingMethod, Disposals, FeeTreatment, InventoryScope, LendingTreatment, LiquidityTreatment, Receipts};
+use crate::accounting::jurisdiction::TaxJurisdiction;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub base_asset: String,
     pub method: AccountingMethod,
-    pub currency_precision: f64,
+    pub currency_precision: Decimal,
+    #[serde(default)]
+    pub check_base_balance: bool,
+    #[serde(default = "default_inventory_scope")]
+    pub inventory_scope: InventoryScope,
+    #[serde(default)]
+    pub jurisdiction: TaxJurisdiction,
+    #[serde(default)]
+    pub receipts: Receipts,
+    #[serde(default)]
+    pub disposals: Disposals,
+    #[serde(default = "default_fees")]
+    pub fees: FeeTreatment,
+    #[serde(default = "default_liquidity")]
+    pub liquidity: LiquidityTreatment,
+    #[serde(default = "default_lending")]
+    pub lending: LendingTreatment,
+    #[serde(default = "default_price_sources")]
+    pub price_sources: Vec<String>,
+    #[serde(default = "default_price_directory")]
+    pub price_directory: String,
+    #[serde(default)]
     pub api_key: HashMap<String, String>,
 }
 
@@ -16,6 +39,33 @@ impl Config {
     pub fn new(config_path: &str) -> Result<Config, Box<dyn error::Error>> {
         let file = std::fs::File::open(config_path)?;
         let config : Config = ::serde_yaml::from_reader(file)?;
+        if config.currency_precision <= Decimal::ZERO {
+            return Err("currency_precision must be positive".into());
+        }
         Ok(config)
     }
-}
\ No newline at end of file
+}
+
+fn default_inventory_scope() -> InventoryScope {
+    InventoryScope::Global
+}
+
+fn default_fees() -> FeeTreatment {
+    FeeTreatment::Neutral
+}
+
+fn default_liquidity() -> LiquidityTreatment {
+    LiquidityTreatment::Taxable
+}
+
+fn default_lending() -> LendingTreatment {
+    LendingTreatment::NonDisposal
+}
+
+fn default_price_sources() -> Vec<String> {
+    vec!["coinapi".to_string()]
+}
+
+fn default_price_directory() -> String {
+    "prices".to_string()
+}
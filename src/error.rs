@@ -0,0 +1,121 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use rust_decimal::Decimal;
+use std::error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CryptoTaxError {
+    /// The transaction file could not be opened or read
+    Input(csv::Error),
+    /// A line of the transaction file could not be parsed
+    Parse {
+        line: u64,
+        source: csv::Error,
+    },
+    /// A transaction lacks a value that its type requires
+    MissingField(&'static str),
+    /// An amount of a transaction is zero or negative, or is used up by its fee
+    InvalidAmount(&'static str),
+    /// An inventory does not hold enough of an asset to cover a withdrawal
+    InsufficientFunds {
+        asset: String,
+        missing: Decimal,
+    },
+    /// A lot named for specific identification is not held in the inventory
+    UnknownLot {
+        asset: String,
+        tx_in: u32,
+    },
+    /// None of the configured price sources could provide a price
+    PriceUnavailable {
+        asset_id_base: String,
+        asset_id_quote: String,
+        datetime: DateTime<Utc>,
+    },
+    /// The price cache could not be read or written
+    PriceCache(Box<dyn error::Error>),
+    /// A report (the capital gains statement, the summary or the income statement) could not be written
+    Output {
+        path: String,
+        source: csv::Error,
+    },
+    /// Processing a transaction failed; wraps the underlying error
+    Transaction {
+        tx_id: u32,
+        line: u64,
+        source: Box<CryptoTaxError>,
+    },
+}
+
+impl CryptoTaxError {
+    /// Process exit code for this error; each class of error has its own code
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CryptoTaxError::Input(_) => 2,
+            CryptoTaxError::Parse { .. } => 3,
+            CryptoTaxError::MissingField(_) => 3,
+            CryptoTaxError::InvalidAmount(_) => 3,
+            CryptoTaxError::InsufficientFunds { .. } => 4,
+            CryptoTaxError::UnknownLot { .. } => 4,
+            CryptoTaxError::PriceUnavailable { .. } => 5,
+            CryptoTaxError::PriceCache(_) => 5,
+            CryptoTaxError::Output { .. } => 6,
+            CryptoTaxError::Transaction { source, .. } => source.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for CryptoTaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoTaxError::Input(err) => {
+                write!(f, "cannot read transaction file: {}", err)
+            },
+            CryptoTaxError::Parse { line, source } => {
+                write!(f, "cannot parse line {} of transaction file: {}", line, source)
+            },
+            CryptoTaxError::MissingField(field) => {
+                write!(f, "missing value for \"{}\"", field)
+            },
+            CryptoTaxError::InvalidAmount(field) => {
+                write!(f, "invalid value for \"{}\": amount must be positive", field)
+            },
+            CryptoTaxError::InsufficientFunds { asset, missing } => {
+                write!(f, "insufficient funds: {} {} missing", missing, asset)
+            },
+            CryptoTaxError::UnknownLot { asset, tx_in } => {
+                write!(f, "lot #{} of {} is not held in the inventory", tx_in, asset)
+            },
+            CryptoTaxError::PriceUnavailable { asset_id_base, asset_id_quote, datetime } => {
+                write!(
+                    f,
+                    "no price source could provide {}/{} at {}",
+                    asset_id_base,
+                    asset_id_quote,
+                    datetime.to_rfc3339_opts(SecondsFormat::Secs, true))
+            },
+            CryptoTaxError::PriceCache(err) => {
+                write!(f, "price cache error: {}", err)
+            },
+            CryptoTaxError::Output { path, source } => {
+                write!(f, "cannot write report \"{}\": {}", path, source)
+            },
+            CryptoTaxError::Transaction { tx_id, line, source } => {
+                write!(f, "transaction #{} (line {}): {}", tx_id, line, source)
+            },
+        }
+    }
+}
+
+impl error::Error for CryptoTaxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CryptoTaxError::Input(err) => Some(err),
+            CryptoTaxError::Parse { source, .. } => Some(source),
+            CryptoTaxError::PriceCache(err) => Some(err.as_ref()),
+            CryptoTaxError::Output { source, .. } => Some(source),
+            CryptoTaxError::Transaction { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
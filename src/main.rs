@@ -1,5 +1,6 @@
 mod app;
 mod config;
+mod error;
 mod prices;
 mod accounting;
 
@@ -7,27 +8,60 @@ use std::process;
 
 use crate::app::App;
 use crate::config::Config;
-use crate::prices::PriceInformation;
+use crate::error::CryptoTaxError;
+use crate::prices::{PriceInformation, PriceSource};
 use crate::accounting::accountant::Accountant;
+use crate::accounting::reports::{IncomeReport, PrivateSalesReport};
 
 fn main() {
     let app = App::new();
 
-    let config = Config::new(app.get_config_path()).unwrap_or_else(|_| {
-        println!("Unable to read config file \"{}\"", app.get_config_path());
+    let config = Config::new(app.get_config_path()).unwrap_or_else(|err| {
+        eprintln!("Unable to read config file \"{}\": {}", app.get_config_path(), err);
         process::exit(1);
     });
 
-    let mut price_information = PriceInformation::new(
-        config.api_key.clone());
+    let price_sources = prices::create_sources(&config).unwrap_or_else(|err| {
+        eprintln!("Invalid price source configuration: {}", err);
+        process::exit(1);
+    });
+
+    if let Err(err) = run(&app, &config, price_sources) {
+        eprintln!("Error: {}", err);
+        process::exit(err.exit_code());
+    }
+}
+
+fn run(app: &App, config: &Config, price_sources: Vec<Box<dyn PriceSource>>) -> Result<(), CryptoTaxError> {
+    let mut price_information = PriceInformation::new(price_sources)?;
     if app.get_clear_cache() {
-        price_information.clear();
+        price_information.clear()?;
     }
 
-    Accountant::new(&mut price_information)
+    let report = Accountant::new(&mut price_information)
         .method(config.method)
         .base_asset(config.base_asset.as_str())
         .precision(config.currency_precision)
-        .analyze_file(app.get_input_path())
-        .write_to_file(app.get_output_path());
+        .check_base_balance(config.check_base_balance)
+        .inventory_scope(config.inventory_scope)
+        .jurisdiction(config.jurisdiction.clone())
+        .receipts(config.receipts)
+        .disposals(config.disposals)
+        .fees(config.fees)
+        .liquidity(config.liquidity)
+        .lending(config.lending)
+        .analyze_file(app.get_input_path())?;
+    report.write_to_file(app.get_output_path())?;
+
+    if let Some(summary_path) = app.get_summary_path() {
+        let summary = PrivateSalesReport::new(&report);
+        summary.print();
+        summary.write_to_file(summary_path)?;
+    }
+
+    if let Some(income_path) = app.get_income_path() {
+        IncomeReport::new(&report).write_to_file(income_path)?;
+    }
+
+    Ok(())
 }
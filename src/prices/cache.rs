@@ -0,0 +1,83 @@
+use chrono::{Utc, DateTime, SecondsFormat};
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use std::error;
+use std::str::FromStr;
+
+/// Persistent store of previously queried prices, backed by a single SQLite file.
+///
+/// Prices are keyed by asset pair, time and the source that provided them.
+/// Each entry also records when it was fetched.
+pub struct PriceCache {
+    connection: Connection,
+}
+
+impl PriceCache {
+    pub fn open(path: &str) -> Result<PriceCache, Box<dyn error::Error>> {
+        let connection = Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS prices (
+                asset_id_base TEXT NOT NULL,
+                asset_id_quote TEXT NOT NULL,
+                time TEXT NOT NULL,
+                source TEXT NOT NULL,
+                rate TEXT NOT NULL,
+                fetched_at TEXT NOT NULL,
+                PRIMARY KEY (asset_id_base, asset_id_quote, time, source)
+            )",
+            [])?;
+
+        Ok(PriceCache {
+            connection,
+        })
+    }
+
+    pub fn clear(&mut self) -> Result<(), Box<dyn error::Error>> {
+        self.connection.execute("DELETE FROM prices", [])?;
+        Ok(())
+    }
+
+    pub fn get(
+        &self,
+        asset_id_base: &str,
+        asset_id_quote: &str,
+        datetime: DateTime<Utc>,
+        source: &str,
+    ) -> Result<Option<Decimal>, Box<dyn error::Error>> {
+        let rate: Option<String> = self.connection
+            .query_row(
+                "SELECT rate FROM prices
+                 WHERE asset_id_base = ?1 AND asset_id_quote = ?2 AND time = ?3 AND source = ?4",
+                params![asset_id_base, asset_id_quote, format_time(datetime), source],
+                |row| row.get(0))
+            .optional()?;
+        Ok(rate.map(|rate| Decimal::from_str(&rate)).transpose()?)
+    }
+
+    pub fn insert(
+        &mut self,
+        asset_id_base: &str,
+        asset_id_quote: &str,
+        datetime: DateTime<Utc>,
+        source: &str,
+        rate: Decimal,
+    ) -> Result<(), Box<dyn error::Error>> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO prices
+                (asset_id_base, asset_id_quote, time, source, rate, fetched_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                asset_id_base,
+                asset_id_quote,
+                format_time(datetime),
+                source,
+                rate.to_string(),
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ])?;
+        Ok(())
+    }
+}
+
+fn format_time(datetime: DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
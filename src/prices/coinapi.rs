@@ -0,0 +1,60 @@
+use chrono::{Utc, DateTime, SecondsFormat};
+use rust_decimal::Decimal;
+use serde::{Deserialize};
+use std::error;
+
+use crate::prices::PriceSource;
+
+#[derive(Deserialize, Debug)]
+struct ExchangeRateRecord {
+    // the rate is parsed from the JSON number as given, without a detour through a float
+    #[serde(with = "rust_decimal::serde::arbitrary_precision")]
+    rate: Decimal,
+}
+
+pub struct CoinApi {
+    api_key: String,
+}
+
+impl CoinApi {
+    pub fn new(api_key: String) -> CoinApi {
+        CoinApi {
+            api_key,
+        }
+    }
+}
+
+impl PriceSource for CoinApi {
+    fn name(&self) -> &str {
+        "coinapi"
+    }
+
+    fn get(
+        &mut self,
+        asset_id_base: &str,
+        asset_id_quote: &str,
+        datetime: DateTime<Utc>
+    ) -> Result<Decimal, Box<dyn error::Error>> {
+        let datetime = datetime.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        // prepare API call to retrieve price
+        let url = format!(
+            "https://rest.coinapi.io/v1/exchangerate/{asset_id_base}/{asset_id_quote}?time={time}",
+            asset_id_base = asset_id_base,
+            asset_id_quote = asset_id_quote,
+            time = datetime);
+
+        let response = reqwest::blocking::Client::new()
+            .get(url)
+            .header("X-CoinAPI-Key", self.api_key.as_str())
+            .send()?
+            .error_for_status()?;
+
+        if let Some(remaining_api_calls) = response.headers().get("x-ratelimit-remaining") {
+            println!("--- {} API calls left", remaining_api_calls.to_str()?);
+        }
+
+        let response_body : ExchangeRateRecord = response.json()?;
+        Ok(response_body.rate)
+    }
+}
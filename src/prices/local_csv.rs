@@ -0,0 +1,97 @@
+use chrono::{Utc, DateTime};
+use rust_decimal::Decimal;
+use serde::{Deserialize};
+use std::collections::HashMap;
+use std::error;
+use std::path::PathBuf;
+
+use crate::prices::PriceSource;
+
+/// Candles further away from the requested time than this are not used
+const MAX_CANDLE_DISTANCE_HOURS: i64 = 24;
+
+#[derive(Deserialize, Debug)]
+struct Candle {
+    time: DateTime<Utc>,
+    close: Decimal,
+}
+
+/// Reads historical prices from local OHLC files, one file per asset pair.
+///
+/// The file for a pair is located at `<directory>/<BASE>-<QUOTE>.csv`
+/// and has the columns `time,open,high,low,close`.
+pub struct LocalCsv {
+    directory: PathBuf,
+    candles: HashMap<(String, String), Vec<Candle>>,
+}
+
+impl LocalCsv {
+    pub fn new(directory: &str) -> LocalCsv {
+        LocalCsv {
+            directory: PathBuf::from(directory),
+            candles: HashMap::new(),
+        }
+    }
+
+    fn load(&self, asset_id_base: &str, asset_id_quote: &str) -> Result<Vec<Candle>, Box<dyn error::Error>> {
+        let path = self.directory.join(format!("{}-{}.csv", asset_id_base, asset_id_quote));
+        let mut reader = csv::Reader::from_path(&path)
+            .map_err(|err| format!("cannot read \"{}\": {}", path.display(), err))?;
+
+        let mut candles = reader
+            .deserialize::<Candle>()
+            .collect::<Result<Vec<Candle>, csv::Error>>()?;
+        candles.sort_by_key(|candle| candle.time);
+
+        Ok(candles)
+    }
+}
+
+impl PriceSource for LocalCsv {
+    fn name(&self) -> &str {
+        "local_csv"
+    }
+
+    fn get(
+        &mut self,
+        asset_id_base: &str,
+        asset_id_quote: &str,
+        datetime: DateTime<Utc>
+    ) -> Result<Decimal, Box<dyn error::Error>> {
+        // load the price file for this pair on first use
+        let key = (asset_id_base.to_string(), asset_id_quote.to_string());
+        if !self.candles.contains_key(&key) {
+            let candles = self.load(asset_id_base, asset_id_quote)?;
+            self.candles.insert(key.clone(), candles);
+        }
+        let candles = &self.candles[&key];
+
+        // pick the candle whose interval contains the requested time, i.e. the latest one that started
+        // no later than that; if there is none within reach, fall back to the nearest candle
+        let max_distance = MAX_CANDLE_DISTANCE_HOURS * 3600;
+        let distance = |candle: &Candle| (candle.time - datetime).num_seconds().abs();
+        let containing = candles
+            .iter()
+            .rev()
+            .find(|candle| candle.time <= datetime)
+            .filter(|candle| distance(candle) <= max_distance);
+        let candle = match containing {
+            Some(candle) => candle,
+            None => {
+                let nearest = candles
+                    .iter()
+                    .min_by_key(|candle| distance(candle))
+                    .ok_or("price file is empty")?;
+                if distance(nearest) > max_distance {
+                    return Err(format!(
+                        "nearest candle ({}) is more than {} hours away",
+                        nearest.time,
+                        MAX_CANDLE_DISTANCE_HOURS).into());
+                }
+                nearest
+            },
+        };
+
+        Ok(candle.close)
+    }
+}
@@ -1,37 +1,75 @@
+mod cache;
+mod coinapi;
+mod local_csv;
+
 use chrono::{Utc, DateTime, SecondsFormat};
-use serde::{Deserialize};
-use std::collections::{HashMap};
-use std::collections::hash_map::Entry::{Occupied, Vacant};
+use rust_decimal::Decimal;
 use std::error;
-use std::fs::File;
 
-const CACHE_FILE_PATH: &str = ".price_cache";
+use crate::config::Config;
+use crate::error::CryptoTaxError;
+use crate::prices::cache::PriceCache;
+use crate::prices::coinapi::CoinApi;
+use crate::prices::local_csv::LocalCsv;
+
+const CACHE_FILE_PATH: &str = ".price_cache.sqlite";
+
+/// A provider of historical exchange rates
+pub trait PriceSource {
+    /// Name of the source, as used in the configuration file
+    fn name(&self) -> &str;
+
+    /// Exchange rate of `asset_id_base` in terms of `asset_id_quote` at the given time
+    fn get(
+        &mut self,
+        asset_id_base: &str,
+        asset_id_quote: &str,
+        datetime: DateTime<Utc>
+    ) -> Result<Decimal, Box<dyn error::Error>>;
+}
 
-#[derive(Deserialize, Debug)]
-pub struct ExchangeRateRecord {
-    time : DateTime<Utc>,
-    asset_id_base : String,
-    asset_id_quote : String,
-    rate: f64,
+/// Creates the chain of price sources listed in the configuration, in the given order
+pub fn create_sources(config: &Config) -> Result<Vec<Box<dyn PriceSource>>, Box<dyn error::Error>> {
+    config.price_sources.iter()
+        .map(|name| -> Result<Box<dyn PriceSource>, Box<dyn error::Error>> {
+            match name.as_str() {
+                "coinapi" => {
+                    let api_key = config.api_key
+                        .get("coinapi")
+                        .ok_or("Price source \"coinapi\" requires an API key")?;
+                    Ok(Box::new(CoinApi::new(api_key.clone())))
+                },
+                "local_csv" => Ok(Box::new(LocalCsv::new(&config.price_directory))),
+                _ => Err(format!("Unknown price source \"{}\"", name).into()),
+            }
+        })
+        .collect()
 }
 
 pub struct PriceInformation {
-    price_cache : HashMap<String, HashMap<DateTime<Utc>, f64>>,
-    api_keys : HashMap<String, String>,
+    price_cache : PriceCache,
+    sources : Vec<Box<dyn PriceSource>>,
 }
 
 impl PriceInformation {
-    pub fn new(api_keys : HashMap<String, String>) -> PriceInformation {
-        let mut res = PriceInformation {
-            price_cache: HashMap::new(),
-            api_keys
-        };
-        res.load().expect("Cannot load price cache!");
-        res
+    pub fn new(sources : Vec<Box<dyn PriceSource>>) -> Result<PriceInformation, CryptoTaxError> {
+        Ok(PriceInformation {
+            price_cache: PriceCache::open(CACHE_FILE_PATH).map_err(CryptoTaxError::PriceCache)?,
+            sources,
+        })
     }
 
-    pub fn clear(&mut self) {
-        self.price_cache.clear();
+    /// Price information with a cache that is not persisted, for tests
+    #[cfg(test)]
+    pub fn in_memory(sources : Vec<Box<dyn PriceSource>>) -> Result<PriceInformation, CryptoTaxError> {
+        Ok(PriceInformation {
+            price_cache: PriceCache::open(":memory:").map_err(CryptoTaxError::PriceCache)?,
+            sources,
+        })
+    }
+
+    pub fn clear(&mut self) -> Result<(), CryptoTaxError> {
+        self.price_cache.clear().map_err(CryptoTaxError::PriceCache)
     }
 
     pub fn get(
@@ -39,90 +77,45 @@ impl PriceInformation {
         asset_id_base: &str,
         asset_id_quote: &str,
         datetime: DateTime<Utc>
-    ) -> f64 {
-        let prices_for_asset = self.price_cache
-            .entry(asset_id_base.to_string())
-            .or_insert(HashMap::new());
-
-        let entry = prices_for_asset.entry(datetime.clone());
+    ) -> Result<Decimal, CryptoTaxError> {
+        // ask each source in turn, until one of them knows the price; a price that a source delivered
+        // before is taken from its cache entry, so that the result does not depend on the cache history
+        for source in self.sources.iter_mut() {
+            let cached = self.price_cache
+                .get(asset_id_base, asset_id_quote, datetime, source.name())
+                .map_err(CryptoTaxError::PriceCache)?;
+            if let Some(price) = cached {
+                return Ok(price);
+            }
 
-        // if price was found in cache, return the cached value
-        if let Occupied(entry) = entry {
-            return entry.get().clone();
-        }
-
-        let datetime = datetime.to_rfc3339_opts(SecondsFormat::Secs, true);
-
-        // prepare API call to retrieve price
-        let url = format!(
-            "https://rest.coinapi.io/v1/exchangerate/{asset_id_base}/{asset_id_quote}?time={time}",
-            asset_id_base = asset_id_base,
-            asset_id_quote = asset_id_quote,
-            time = datetime);
-
-        if let Vacant(entry) = entry {
             println!(
-                "--- running price query: {}/{}, {}",
+                "--- running price query: {}/{}, {} ({})",
                 asset_id_base,
                 asset_id_quote,
-                datetime,
+                datetime.to_rfc3339_opts(SecondsFormat::Secs, true),
+                source.name(),
             );
 
-            let api_key = self.api_keys
-                .get("coinapi")
-                .expect("Currently only supporting CoinAPI");
-
-            let response = reqwest::blocking::Client::new()
-                .get(url)
-                .header("X-CoinAPI-Key", api_key)
-                .send()
-                .unwrap();
-            let remaining_api_calls = String::from(response
-                .headers()
-                .get("x-ratelimit-remaining")
-                .unwrap()
-                .to_str()
-                .unwrap());
-            let response_body : ExchangeRateRecord = response
-                .json()
-                .unwrap();
-            let price = response_body.rate;
-
-            println!("--- success: price = {}",  price);
-            println!("--- {} API calls left", remaining_api_calls);
-
-            entry.insert(price);
-
-            // TODO: remove this save once the app becomes more stable
-            self.save().expect("Could not save price cache!");
-
-            return price;
-        }
+            match source.get(asset_id_base, asset_id_quote, datetime) {
+                Ok(price) => {
+                    println!("--- success: price = {}",  price);
 
-        panic!("Should never reach here");
-    }
+                    self.price_cache
+                        .insert(asset_id_base, asset_id_quote, datetime, source.name(), price)
+                        .map_err(CryptoTaxError::PriceCache)?;
 
-    fn load(&mut self) -> Result<(), Box<dyn error::Error>> {
-        self.clear();
-
-        if let Ok(file) = std::fs::File::open(CACHE_FILE_PATH) {
-            let data = ::serde_yaml::from_reader(file)?;
-            self.price_cache = data;
+                    return Ok(price);
+                },
+                Err(err) => {
+                    println!("--- failed: {}", err);
+                },
+            }
         }
 
-        Ok(())
-    }
-
-    fn save(&self) -> Result<(), Box<dyn error::Error>> {
-        ::serde_yaml::to_writer(
-            &File::create(CACHE_FILE_PATH)?,
-            &self.price_cache)?;
-        Ok(())
+        Err(CryptoTaxError::PriceUnavailable {
+            asset_id_base: asset_id_base.to_string(),
+            asset_id_quote: asset_id_quote.to_string(),
+            datetime,
+        })
     }
 }
-
-impl Drop for PriceInformation {
-    fn drop(&mut self) {
-        self.save().expect("Could not save price cache!");
-    }
-}
\ No newline at end of file